@@ -1,38 +1,79 @@
-use std::{thread, time::Duration};
+use std::time::Duration;
 
-use curl::easy::Easy;
-use regex::Captures;
+use futures_util::future::BoxFuture;
 
-use crate::bot::Bot;
+use crate::context::Context;
+
+/// brain namespace of `Action::remember` and `Action::whatis`
+pub const FACTOIDS: &str = "factoids";
 
 pub struct Action {}
 
 impl Action {
-    pub fn ping(bot: &Bot, ch: String, nick: String, _msg: String, _caps: Captures) {
-        bot.reply(&ch, &nick, "pong");
+    pub fn ping(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Err(e) = ctx.reply("pong").await {
+                log::error!("reply fail: {e}");
+            }
+        })
+    }
+
+    /// `<key> is <value>` teaches the bot a factoid
+    pub fn remember(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        if let (Some(k), Some(v)) = (ctx.group(1), ctx.group(2)) {
+            if let Err(e) = ctx.brain().namespace(FACTOIDS).set(k, &v) {
+                log::error!("remember fail: {e}");
+            }
+        }
+        Box::pin(async {})
+    }
+
+    /// `<key>?` answers with the factoid if known
+    pub fn whatis(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let Some(v) = ctx
+                .group(1)
+                .and_then(|k| ctx.brain().namespace(FACTOIDS).get::<String>(k))
+            else {
+                return;
+            };
+            if let Err(e) = ctx.send(&v).await {
+                log::error!("send fail: {e}");
+            }
+        })
     }
 
-    pub fn ping_delayed(bot: &Bot, ch: String, _nick: String, _msg: String, _caps: Captures) {
-        let serv = bot.server.clone();
-        thread::spawn(move || {
+    pub fn ping_delayed(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        let serv = ctx.bot.server.clone();
+        let ch = ctx.channel().to_string();
+        ctx.bot.spawn(async move {
             // a long task here
-            thread::sleep(Duration::from_secs(1));
-            serv.lock().unwrap().send(&ch, "pong");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Err(e) = serv.send(&ch, "pong").await {
+                log::error!("send fail: {e}");
+            }
         });
+        Box::pin(async {})
     }
 
-    pub fn ifconfig(bot: &Bot, ch: String, _nick: String, _msg: String, _caps: Captures) {
-        let serv = bot.server.clone();
-        thread::spawn(move || {
-            let mut easy = Easy::new();
-            easy.url("https://ifconfig.me/").unwrap();
-            easy.write_function(move |data| {
-                let s = std::str::from_utf8(data).unwrap();
-                serv.lock().unwrap().send(&ch, s);
-                Ok(data.len())
-            })
-            .unwrap();
-            easy.perform().unwrap();
+    pub fn ifconfig(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        let serv = ctx.bot.server.clone();
+        let ch = ctx.channel().to_string();
+        let http = ctx.http();
+        ctx.bot.spawn(async move {
+            let ip = match http.get("https://ifconfig.me/").send().await {
+                Ok(resp) => resp.text().await,
+                Err(e) => Err(e),
+            };
+            match ip {
+                Ok(ip) => {
+                    if let Err(e) = serv.send(&ch, &ip).await {
+                        log::error!("send fail: {e}");
+                    }
+                }
+                Err(e) => log::error!("ifconfig fail: {e}"),
+            }
         });
+        Box::pin(async {})
     }
 }
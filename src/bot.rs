@@ -1,61 +1,163 @@
 use std::{
     collections::HashMap,
-    fs::File,
-    hash::{Hash, Hasher},
-    io::{Read, Write},
-    str::FromStr,
-    sync::{mpsc::channel, Arc, Mutex},
-    thread::JoinHandle,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
 };
 
-use regex::{Captures, Regex};
+use anyhow::{Context as _, Result};
+use futures_util::future::BoxFuture;
+use regex::escape;
 use serde::Deserialize;
+use tokio::{
+    sync::{mpsc::unbounded_channel, Notify},
+    task::{self, JoinHandle},
+    time::interval,
+};
 
 use crate::{
     action::Action,
-    config::Config,
+    brain::{Brain, BrainStore},
+    bridge::{Bridge, MAIN},
+    config::{Config, ConnectionConfig},
+    context::Context,
     http::serve,
-    server::{irc::Irc, shell::Shell, Server},
+    matcher::{Any, Kind, Matcher, Pattern},
+    middleware::{chain, Chain, Middleware, Output},
+    server::{
+        discord::Discord, irc::Irc, matrix::Matrix, mattermost::Mattermost, shell::Shell,
+        slack::Slack, telegram::Telegram, webhook::Webhook, xmpp::Xmpp, Capabilities, SendResult,
+        Server,
+    },
 };
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum ServerType {
     Shell,
     Irc,
+    Slack,
+    Discord,
+    Matrix,
+    Telegram,
+    Mattermost,
+    Xmpp,
+    Webhook,
 }
 
-type Callback = Box<dyn Fn(&Bot, String, String, String, Captures)>;
-
-// Regex does not impl PartialEq, Eq, Hash trait
-struct MyRegex(regex::Regex);
-
-impl PartialEq for MyRegex {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.as_str().eq(other.0.as_str())
+impl ServerType {
+    /// adapter configured by its section in `config`
+    pub fn build(&self, config: &Config) -> Arc<dyn Server> {
+        let missing = "missing config";
+        let connection = match self {
+            ServerType::Shell => ConnectionConfig::Shell(config.shell.clone().unwrap_or_default()),
+            ServerType::Irc => ConnectionConfig::Irc(config.irc.clone().expect(missing)),
+            ServerType::Slack => ConnectionConfig::Slack(config.slack.clone().expect(missing)),
+            ServerType::Discord => {
+                ConnectionConfig::Discord(config.discord.clone().expect(missing))
+            }
+            ServerType::Matrix => ConnectionConfig::Matrix(config.matrix.clone().expect(missing)),
+            ServerType::Telegram => {
+                ConnectionConfig::Telegram(config.telegram.clone().expect(missing))
+            }
+            ServerType::Mattermost => {
+                ConnectionConfig::Mattermost(config.mattermost.clone().expect(missing))
+            }
+            ServerType::Xmpp => ConnectionConfig::Xmpp(config.xmpp.clone().expect(missing)),
+            ServerType::Webhook => {
+                ConnectionConfig::Webhook(config.webhook.clone().expect(missing))
+            }
+        };
+        connection.build(&config.name)
     }
 }
-impl Eq for MyRegex {}
 
-impl MyRegex {
-    fn from_str(pat: &str) -> Self {
-        MyRegex(Regex::from_str(pat).unwrap())
+impl ConnectionConfig {
+    /// adapter for the bot called `name`
+    pub fn build(&self, name: &str) -> Arc<dyn Server> {
+        // https://rust-unofficial.github.io/patterns/idioms/on-stack-dyn-dispatch.html
+        let name = name.to_string();
+        match self.clone() {
+            ConnectionConfig::Shell(config) => Arc::new(Shell::new(name, config)),
+            ConnectionConfig::Irc(config) => Arc::new(Irc::new(config)),
+            ConnectionConfig::Slack(config) => Arc::new(Slack::new(name, config)),
+            ConnectionConfig::Discord(config) => Arc::new(Discord::new(name, config)),
+            ConnectionConfig::Matrix(config) => Arc::new(Matrix::new(config)),
+            ConnectionConfig::Telegram(config) => Arc::new(Telegram::new(name, config)),
+            ConnectionConfig::Mattermost(config) => Arc::new(Mattermost::new(name, config)),
+            ConnectionConfig::Xmpp(config) => Arc::new(Xmpp::new(name, config)),
+            ConnectionConfig::Webhook(config) => Arc::new(Webhook::new(config)),
+        }
     }
 }
 
-impl Hash for MyRegex {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.as_str().hash(state);
-    }
+type Callback = Box<dyn for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageKind {
+    Text,
+    /// action such as `/me waves`, the message holds the action text
+    Emote,
+    /// watched nick came online, see `Bot::monitor` and `Bot::presence`
+    Online,
+    /// watched nick went offline
+    Offline,
+    /// nick joined the channel, the message is empty
+    Join,
+    /// nick left the channel, the message holds the reason if any
+    Part,
 }
 
+#[derive(Clone, Debug)]
 pub struct Message {
     pub channel: String,
     pub nick: String,
     pub message: String,
+    pub kind: MessageKind,
+    /// when it was sent, the server time if the adapter knows it,
+    /// otherwise when it was received
+    pub time: SystemTime,
+    /// id given by the server, e.g. for `Server::react`
+    pub id: Option<String>,
+    /// sent to the bot privately rather than in a channel
+    pub direct: bool,
+    /// adapter it came in on, unset for messages not from an adapter
+    pub server: Option<ServerType>,
+    /// name of the connection it came in on, see `Config::connections`
+    pub connection: Option<String>,
+    /// host of the user, `user@host` on irc
+    pub host: Option<String>,
+    /// account or user id of the sender on the server
+    pub account: Option<String>,
+    /// line or event as received
+    pub raw: Option<String>,
+    /// IRCv3 message tags
+    pub tags: HashMap<String, String>,
 }
 
 impl Message {
+    /// message received now, without metadata
+    pub fn new(channel: &str, nick: &str, message: &str, kind: MessageKind) -> Self {
+        Message {
+            channel: channel.to_string(),
+            nick: nick.to_string(),
+            message: message.to_string(),
+            kind,
+            time: SystemTime::now(),
+            id: None,
+            direct: false,
+            server: None,
+            connection: None,
+            host: None,
+            account: None,
+            raw: None,
+            tags: HashMap::new(),
+        }
+    }
+
     pub fn trim(&self) -> &str {
         self.message.trim()
     }
@@ -63,208 +165,561 @@ impl Message {
 
 pub struct Bot {
     name: String,
-    reaction: HashMap<MyRegex, Callback>,
-    resp: HashMap<MyRegex, Callback>,
-    state: HashMap<String, String>,
-    pub server: Arc<Mutex<Box<dyn Server + Send>>>,
+    /// in the order they run, see `Bot::hear_priority`
+    handlers: Vec<Handler>,
+    brain: Brain,
+    /// brain is saved on shutdown and every `autosave` when set
+    store: Option<Arc<dyn BrainStore>>,
+    autosave: Option<Duration>,
+    /// builtin http server is not started when unset
+    http_addr: Option<String>,
+    pending: Arc<Pending>,
+    middleware: Chain,
+    /// relays messages to other channels when set
+    bridge: Option<Arc<Bridge>>,
+    /// output passes the response middleware
+    pub server: Arc<dyn Server>,
 }
 
-const STATE_FILE: &str = "state.dat";
+// seconds
+const AUTOSAVE: u64 = 300;
+const PURGE: u64 = 60;
+const HTTP_ADDR: &str = "127.0.0.1:8080";
 
 impl Bot {
     pub fn new(config: Config) -> Self {
-        // https://rust-unofficial.github.io/patterns/idioms/on-stack-dyn-dispatch.html
-        let server: Box<dyn Server + Send> = match config.server {
-            ServerType::Shell => Box::new(Shell::new(config.name.clone())),
-            ServerType::Irc => Box::new(Irc::new(
-                config.irc.as_ref().expect("missing config").clone(),
-            )),
-        };
-
-        // load state
-        let mut f = File::options()
-            .read(true)
-            .open(STATE_FILE)
-            .expect("state file open fail");
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf).expect("state file read fail");
-        let state: HashMap<String, String> = if !buf.is_empty() {
-            bincode::deserialize(&buf).expect("state deserialize fail")
-        } else {
-            HashMap::new()
-        };
-
-        // log::trace!("{:#?}", state);
+        let server = config.server.build(&config);
+
+        let brain = config.brain.clone().unwrap_or_default();
+        let store = brain
+            .store
+            .unwrap_or_default()
+            .build(&brain)
+            .expect("brain store open fail");
+        let state = store.load().expect("brain load fail");
+        let autosave = brain.autosave.unwrap_or(AUTOSAVE);
+
+        let bridge = config
+            .bridge
+            .is_some()
+            .then(|| Bridge::new(server.clone(), &config));
+        let mut bot = Bot::with_server(config.name, server);
+        bot.bridge = bridge.map(Arc::new);
+        bot.brain = Brain::new(state);
+        bot.store = Some(Arc::from(store));
+        bot.autosave = (autosave > 0).then(|| Duration::from_secs(autosave));
+        // adapters with routes need it even when no address is configured
+        let routes = !bot.server.routes().is_empty();
+        bot.http_addr = config
+            .http_addr
+            .or_else(|| routes.then(|| HTTP_ADDR.to_string()));
+        bot
+    }
 
+    /// bot on the given server, state starts empty and is not persisted,
+    /// and `run` does not start the http server
+    pub fn with_server(name: String, server: Arc<dyn Server>) -> Self {
+        let middleware = Chain::default();
         Bot {
-            name: config.name,
-            reaction: HashMap::new(),
-            resp: HashMap::new(),
-            server: Arc::new(Mutex::new(server)),
-            state,
+            name,
+            handlers: Vec::new(),
+            server: Arc::new(Output {
+                inner: server,
+                middleware: middleware.clone(),
+            }),
+            brain: Brain::default(),
+            store: None,
+            autosave: None,
+            http_addr: None,
+            pending: Arc::default(),
+            middleware,
+            bridge: None,
         }
     }
 
-    pub fn hear<F>(&mut self, pattern: &str, cb: F)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hear<F>(&mut self, pattern: &str, cb: F) -> Result<(), regex::Error>
     where
-        F: Fn(&Bot, String, String, String, Captures) + 'static,
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
     {
-        let re = MyRegex::from_str(pattern);
-        self.reaction.entry(re).or_insert_with(|| Box::new(cb));
+        self.hear_priority(pattern, 0, cb)
     }
 
-    pub fn respond<F>(&mut self, pattern: &str, cb: F)
+    pub fn respond<F>(&mut self, pattern: &str, cb: F) -> Result<(), regex::Error>
     where
-        F: Fn(&Bot, String, String, String, Captures) + 'static,
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
     {
-        let pat = format!("{}:? +?{}", self.name, pattern);
-        let re = MyRegex::from_str(&pat);
-        self.resp.entry(re).or_insert_with(|| Box::new(cb));
+        self.respond_priority(pattern, 0, cb)
     }
 
-    pub fn send(&self, channel: &str, message: &str) {
-        self.server.lock().unwrap().send(channel, message);
+    /// handlers run by priority, highest first, `respond` handlers before
+    /// `hear` ones of the same priority and then in registration order,
+    /// until one calls `Context::finish`
+    pub fn hear_priority<F>(
+        &mut self,
+        pattern: &str,
+        priority: i32,
+        cb: F,
+    ) -> Result<(), regex::Error>
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    {
+        let matcher = Pattern::new(pattern)?;
+        self.add_handler(Box::new(matcher), priority, false, Box::new(cb));
+        Ok(())
     }
 
-    pub fn reply(&self, channel: &str, nick: &str, message: &str) {
-        self.server
-            .lock()
-            .unwrap()
-            .send(channel, &format!("{}: {}", nick, message));
+    /// like `hear_priority` for messages addressed to the bot, the message
+    /// has to start with its name
+    pub fn respond_priority<F>(
+        &mut self,
+        pattern: &str,
+        priority: i32,
+        cb: F,
+    ) -> Result<(), regex::Error>
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    {
+        // the pattern follows the name, it can't anchor itself
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let matcher = Pattern::new(&format!("^{}:? +?{}", escape(&self.name), pattern))?;
+        self.add_handler(Box::new(matcher), priority, true, Box::new(cb));
+        Ok(())
     }
 
-    pub fn set(&mut self, k: &str, v: &str) {
-        self.state.insert(k.to_string(), v.to_string());
+    /// handler for messages and events the matcher accepts, ordered like
+    /// `hear_priority`
+    pub fn on<M, F>(&mut self, matcher: M, priority: i32, cb: F)
+    where
+        M: Matcher + 'static,
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    {
+        self.add_handler(Box::new(matcher), priority, false, Box::new(cb));
     }
 
-    pub fn get(&self, k: &str) -> Option<&String> {
-        self.state.get(k)
+    fn add_handler(
+        &mut self,
+        matcher: Box<dyn Matcher>,
+        priority: i32,
+        respond: bool,
+        callback: Callback,
+    ) {
+        if let Some(description) = matcher.describe() {
+            let duplicate = self
+                .handlers
+                .iter()
+                .any(|h| h.matcher.describe().as_ref() == Some(&description));
+            if duplicate {
+                log::warn!("duplicate pattern {:?}", description);
+            }
+        }
+        let handler = Handler {
+            matcher,
+            priority,
+            respond,
+            callback,
+        };
+        // after every handler that runs before it
+        let i = self
+            .handlers
+            .iter()
+            .position(|h| handler.runs_before(h))
+            .unwrap_or(self.handlers.len());
+        self.handlers.insert(i, handler);
     }
 
-    pub fn run(&mut self) {
-        let (http_server, mut workers_handle) = serve("127.0.0.1:8080").unwrap();
+    /// appends to the middleware chain, see `Middleware`
+    pub fn middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.write().unwrap().push(Arc::new(middleware));
+    }
 
-        let server = self.server.clone();
-        let (tx, rx) = channel::<Message>();
-        let handle = server.lock().unwrap().connect(tx).unwrap();
+    /// handler for presence changes of watched nicks, see `Context::online`
+    pub fn presence<F>(&mut self, cb: F)
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, ()> + Send + Sync + 'static,
+    {
+        let matcher = Any(vec![
+            Box::new(Kind(MessageKind::Online)),
+            Box::new(Kind(MessageKind::Offline)),
+        ]);
+        self.on(matcher, 0, cb);
+    }
 
-        // Global reserved pattern
-        // TODO fix this shit
-        // I'd like to handle it in the action hook,
-        // but I don't know how to modify the mutable hashmap in the closure of immutable loop (self.resp)
-        // http://smallcultfollowing.com/babysteps/blog/2018/11/01/after-nll-interprocedural-conflicts/
-        //
-        // you> bot: key is value # kv.set("key", "value")
-        let pat_kv = MyRegex::from_str(&format!("^{}:? +?{}", self.name, "(.+) is (.+)$"));
-        // you> bot: key?
-        // bot> value
-        let pat_whatis = MyRegex::from_str(&format!("^{}:? +?{}", self.name, "(.+)\\?$"));
+    /// relays messages of the bot server through `bridge`
+    pub fn bridge(&mut self, bridge: Bridge) {
+        self.bridge = Some(Arc::new(bridge));
+    }
 
-        loop {
-            let msg = rx.recv().unwrap();
-            let text = msg.trim();
+    pub async fn monitor(&self, nick: &str) {
+        self.server.monitor(&[nick.to_string()]).await;
+    }
 
-            if has_shutdown(&self.name, &text.to_lowercase()) {
-                self.shutdown(Some(msg));
-                break;
-            }
+    pub async fn unmonitor(&self, nick: &str) {
+        self.server.unmonitor(&[nick.to_string()]).await;
+    }
+
+    pub async fn send(&self, channel: &str, message: &str) -> SendResult {
+        self.server.send(channel, message).await
+    }
+
+    pub async fn reply(&self, channel: &str, nick: &str, message: &str) -> SendResult {
+        self.server.reply(channel, nick, message).await
+    }
+
+    pub async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        self.server.emote(channel, action).await
+    }
+
+    pub async fn notice(&self, channel: &str, message: &str) -> SendResult {
+        self.server.notice(channel, message).await
+    }
+
+    pub async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        self.server.topic(channel, topic).await
+    }
+
+    pub async fn join(&self, channel: &str) -> SendResult {
+        self.server.join(channel).await
+    }
 
-            if let Some(caps) = pat_kv.0.captures(text) {
-                self.set(caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str());
+    pub async fn part(&self, channel: &str) -> SendResult {
+        self.server.part(channel).await
+    }
+
+    pub async fn react(&self, channel: &str, id: &str, reaction: &str) -> SendResult {
+        self.server.react(channel, id, reaction).await
+    }
+
+    pub async fn send_thread_reply(
+        &self,
+        channel: &str,
+        thread: &str,
+        message: &str,
+    ) -> SendResult {
+        self.server
+            .send_thread_reply(channel, thread, message)
+            .await
+    }
+
+    /// what the current adapter supports
+    pub fn capabilities(&self) -> Capabilities {
+        self.server.capabilities()
+    }
+
+    /// runs background work as a task, shutdown waits for it to finish,
+    /// handlers use it for anything slow, see `Bot::receive`
+    pub fn spawn<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = PendingGuard::new(self.pending.clone());
+        tokio::spawn(async move {
+            // decremented on drop, even if f panics
+            let _guard = guard;
+            f.await;
+        })
+    }
+
+    /// waits until background work started by `spawn` is done
+    pub async fn wait(&self) {
+        loop {
+            // registered before the check so a wakeup in between is not lost
+            let notified = self.pending.done.notified();
+            if *self.pending.count.lock().unwrap() == 0 {
+                return;
             }
+            notified.await;
+        }
+    }
+
+    /// string in the default namespace of the brain
+    pub fn set(&self, k: &str, v: &str) {
+        // strings always serialize
+        self.brain.set(k, &v).ok();
+    }
 
-            if let Some(caps) = pat_whatis.0.captures(text) {
-                if let Some(v) = self.get(caps.get(1).unwrap().as_str()) {
-                    self.send(&msg.channel, v);
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.brain.get(k)
+    }
+
+    /// shared store, clone it to use it from background tasks
+    pub fn brain(&self) -> &Brain {
+        &self.brain
+    }
+
+    /// connects and dispatches messages until shutdown, fails if the http
+    /// server can't listen or the adapter can't connect
+    pub async fn run(&mut self) -> Result<()> {
+        let http = match &self.http_addr {
+            Some(addr) => Some(
+                serve(addr, self.server.routes())
+                    .await
+                    .with_context(|| format!("http server on {addr} fail"))?,
+            ),
+            None => None,
+        };
+
+        let (tx, mut rx) = unbounded_channel::<Message>();
+        let handle = match self.server.connect(tx).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                if let Some(http) = http {
+                    http.shutdown().await;
                 }
+                return Err(e.context("connect fail"));
             }
+        };
+        let mut handles = vec![handle];
+        if let Some(bridge) = &self.bridge {
+            handles.extend(bridge.connect().await);
+        }
 
-            for (pattern, cb) in &self.resp {
-                if let Some(caps) = pattern.0.captures(text) {
-                    cb(
-                        self,
-                        msg.channel.clone(),
-                        msg.nick.clone(),
-                        text.to_string(),
-                        caps,
-                    );
+        let autosave = self.autosave.map(|period| {
+            let brain = self.brain.clone();
+            let store = self.store.clone();
+            tokio::spawn(async move {
+                let mut ticker = interval(period);
+                // the first tick completes immediately
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    save(store.clone(), brain.clone()).await;
+                }
+            })
+        });
+
+        let brain = self.brain.clone();
+        let purge = tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(PURGE));
+            loop {
+                ticker.tick().await;
+                let purged = brain.purge();
+                if purged > 0 {
+                    log::trace!("brain purged {purged} expired keys");
                 }
             }
+        });
 
-            for (pattern, cb) in &self.reaction {
-                if let Some(caps) = pattern.0.captures(text) {
-                    cb(
-                        self,
-                        msg.channel.clone(),
-                        msg.nick.clone(),
-                        text.to_string(),
-                        caps,
-                    );
-                }
+        loop {
+            // every sender is gone, e.g. the shell reached the end of input
+            let Some(mut msg) = rx.recv().await else {
+                self.shutdown(None).await;
+                break;
+            };
+            msg.connection = Some(MAIN.to_string());
+            if !self.receive(msg).await {
+                break;
             }
         }
 
-        // graceful shutdown http server
-        for _ in 0..workers_handle.len() {
-            http_server.unblock();
+        purge.abort();
+        if let Some(autosave) = autosave {
+            autosave.abort();
         }
+        if let Some(http) = http {
+            http.shutdown().await;
+        }
+        self.finalize(handles).await;
+        Ok(())
+    }
 
-        let mut join_handles = vec![handle];
-        join_handles.append(&mut workers_handle);
-        self.finalize(join_handles);
+    /// dispatches a message to the handlers, returns false once the bot shut down
+    ///
+    /// Handlers are awaited one after another in this loop, one awaiting
+    /// slow I/O holds up every message on every connection. Long work goes
+    /// through `Bot::spawn` and the handler returns right away.
+    pub async fn receive(&mut self, mut msg: Message) -> bool {
+        for m in chain(&self.middleware) {
+            if !m.receive(self, &mut msg).await {
+                return true;
+            }
+        }
+
+        if let Some(bridge) = &self.bridge {
+            bridge.relay(&msg).await;
+        }
+        let text = msg.trim();
+
+        if has_shutdown(&self.name, &text.to_lowercase()) {
+            self.shutdown(Some(msg)).await;
+            return false;
+        }
+
+        let finished = AtomicBool::new(false);
+        for handler in &self.handlers {
+            if let Some(matched) = handler.matcher.matches(&msg) {
+                let ctx = Context::new(self, &msg, matched.captures, &finished);
+                if !self.listen(&ctx).await {
+                    continue;
+                }
+                (handler.callback)(ctx).await;
+                if finished.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// runs the listener middleware, false if the handler is skipped
+    async fn listen(&self, ctx: &Context<'_>) -> bool {
+        for m in chain(&self.middleware) {
+            if !m.listener(ctx).await {
+                return false;
+            }
+        }
+        true
     }
 
-    pub fn shutdown(&self, msg: Option<Message>) {
+    pub async fn shutdown(&self, msg: Option<Message>) {
         log::trace!("shutdown");
+        self.wait().await;
         if let Some(msg) = msg {
-            self.send(&msg.channel, "bye");
+            if let Err(e) = self.send(&msg.channel, "bye").await {
+                log::error!("send fail: {e}");
+            }
+        }
+        self.server.disconnect().await;
+        if let Some(bridge) = &self.bridge {
+            bridge.disconnect().await;
         }
-        self.server.lock().unwrap().disconnect();
 
-        // dump state to file
-        let mut f = File::options()
-            .create(true)
-            .write(true)
-            .open(STATE_FILE)
-            .unwrap();
-        let data = bincode::serialize(&self.state).expect("state serialize fail");
-        f.write_all(&data).expect("write state file fail");
+        self.save().await;
+    }
+
+    /// writes the brain to the store if there is one
+    pub async fn save(&self) {
+        save(self.store.clone(), self.brain.clone()).await;
     }
 
-    pub fn finalize(&self, handles: Vec<JoinHandle<()>>) {
+    pub async fn finalize(&self, handles: Vec<JoinHandle<()>>) {
         log::trace!("finalize...");
         for handle in handles {
-            handle.join().expect("join fail");
+            handle.await.expect("join fail");
         }
         log::trace!("finalize...done");
     }
 
     pub fn install_actions(&mut self) {
         // conditional install?
-        self.respond("ping", Action::ping);
-        self.respond("ipaddr", Action::ifconfig);
+        self.respond("ping", Action::ping)
+            .expect("ping pattern fail");
+        self.respond("ipaddr", Action::ifconfig)
+            .expect("ipaddr pattern fail");
+        // you> bot: key is value
+        self.respond("(.+) is (.+)$", Action::remember)
+            .expect("factoid pattern fail");
+        // you> bot: key?
+        // bot> value
+        self.respond(r"(.+)\?$", Action::whatis)
+            .expect("factoid pattern fail");
+    }
+}
+
+struct Handler {
+    matcher: Box<dyn Matcher>,
+    priority: i32,
+    /// registered with `respond`
+    respond: bool,
+    callback: Callback,
+}
+
+impl Handler {
+    fn runs_before(&self, other: &Handler) -> bool {
+        self.priority > other.priority
+            || (self.priority == other.priority && self.respond && !other.respond)
+    }
+}
+
+/// number of running background tasks, see `Bot::spawn`
+#[derive(Default)]
+struct Pending {
+    count: Mutex<usize>,
+    done: Notify,
+}
+
+struct PendingGuard(Arc<Pending>);
+
+impl PendingGuard {
+    fn new(pending: Arc<Pending>) -> Self {
+        *pending.count.lock().unwrap() += 1;
+        PendingGuard(pending)
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap_or_else(|e| e.into_inner());
+        *count -= 1;
+        if *count == 0 {
+            self.0.done.notify_waiters();
+        }
+    }
+}
+
+async fn save(store: Option<Arc<dyn BrainStore>>, brain: Brain) {
+    let Some(store) = store else {
+        return;
+    };
+    // stores do blocking io
+    let result = task::spawn_blocking(move || store.save(&brain.snapshot())).await;
+    match result {
+        Ok(Ok(())) => log::trace!("brain saved"),
+        Ok(Err(e)) => log::error!("brain save fail: {e}"),
+        Err(e) => log::error!("brain save fail: {e}"),
     }
 }
 
 fn has_shutdown(name: &str, s: &str) -> bool {
-    if name.len() >= s.len() || name.ne(&s[0..name.len()]) {
+    let Some(rest) = s.strip_prefix(name) else {
+        return false;
+    };
+    // skip the separator after the name, which may be any character
+    let mut rest = rest.chars();
+    if rest.next().is_none() {
         return false;
     }
-    matches!(s[(name.len() + 1)..].trim(), "shutdown" | "exit" | "quit")
+    matches!(rest.as_str().trim(), "shutdown" | "exit" | "quit")
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::future::BoxFuture;
     use regex::Regex;
+    use tokio::time::sleep;
+
+    use crate::{
+        action::Action,
+        context::Context,
+        matcher::Kind,
+        server::test::{Sent, TestServer},
+    };
+
+    use super::{has_shutdown, Bot, Message, MessageKind};
+
+    fn say<'a>(ctx: Context<'a>, text: &'static str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            ctx.send(text).await.unwrap();
+        })
+    }
 
     #[test]
     fn test_has_shutdown() {
-        let s = "hongbot: exit";
-        let name = "hongbot";
-        assert!(name.eq(&s[0..name.len()]));
-        assert_eq!(name, &s[0..name.len()]);
-        assert_eq!(&s[(name.len() + 2)..], "exit");
-        assert_eq!(s[(name.len() + 1)..].trim(), "exit");
+        assert!(has_shutdown("hongbot", "hongbot: exit"));
+        assert!(has_shutdown("hongbot", "hongbot quit"));
+        assert!(has_shutdown("hongbot", "hongbot아 shutdown"));
+        assert!(!has_shutdown("hongbot", "hongbot"));
+        assert!(!has_shutdown("hongbot", "hongbot아 ping"));
+        assert!(!has_shutdown("hongbot", "hong아"));
     }
 
     #[test]
@@ -280,4 +735,128 @@ mod tests {
         assert_eq!(caps.get(0).unwrap().as_str(), "ping 5");
         assert_eq!(caps.get(1).unwrap().as_str(), "5");
     }
+
+    #[tokio::test]
+    async fn test_handler_order() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.hear("ping", |ctx| say(ctx, "hear")).unwrap();
+        bot.respond("ping", |ctx| say(ctx, "respond")).unwrap();
+        bot.hear_priority("ping", 10, |ctx| say(ctx, "first"))
+            .unwrap();
+        // duplicates are kept, in registration order
+        bot.respond("ping", |ctx| say(ctx, "respond again"))
+            .unwrap();
+
+        let ping = Message::new("#ops", "alice", "hongbot: ping", MessageKind::Text);
+        bot.receive(ping.clone()).await;
+        let sent = server.drain(&bot).await;
+        assert_eq!(
+            sent,
+            vec![
+                ("#ops", "first").into(),
+                ("#ops", "respond").into(),
+                ("#ops", "respond again").into(),
+                ("#ops", "hear").into(),
+            ]
+        );
+
+        bot.respond_priority("ping", 5, |ctx| {
+            Box::pin(async move {
+                ctx.finish();
+                ctx.send("handled").await.unwrap();
+            })
+        })
+        .unwrap();
+        bot.receive(ping).await;
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![("#ops", "first").into(), ("#ops", "handled").into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.respond("slow", |ctx| {
+            let server = ctx.bot.server.clone();
+            let channel = ctx.channel().to_string();
+            ctx.bot.spawn(async move {
+                sleep(Duration::from_millis(300)).await;
+                server.send(&channel, "done").await.unwrap();
+            });
+            Box::pin(async {})
+        })
+        .unwrap();
+        bot.respond("ping", Action::ping).unwrap();
+
+        let started = Instant::now();
+        bot.receive(Message::new(
+            "#ops",
+            "alice",
+            "hongbot: slow",
+            MessageKind::Text,
+        ))
+        .await;
+        bot.receive(Message::new(
+            "#ops",
+            "bob",
+            "hongbot: ping",
+            MessageKind::Text,
+        ))
+        .await;
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![
+                Sent::Reply {
+                    channel: "#ops".to_string(),
+                    nick: "bob".to_string(),
+                    message: "pong".to_string(),
+                },
+                ("#ops", "done").into(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_bind_fail() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server));
+        bot.http_addr = Some(taken.local_addr().unwrap().to_string());
+        assert!(bot.run().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_matchers() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        assert!(bot.hear("(", |ctx| say(ctx, "never")).is_err());
+        bot.on(Kind(MessageKind::Join), 0, |ctx| say(ctx, "welcome"));
+        bot.on(Kind(MessageKind::Offline), 1, |ctx| say(ctx, "gone"));
+        bot.presence(|ctx| {
+            let text = if ctx.online() { "online" } else { "offline" };
+            say(ctx, text)
+        });
+
+        bot.receive(Message::new("#ops", "alice", "", MessageKind::Join))
+            .await;
+        bot.receive(Message::new("#ops", "alice", "(", MessageKind::Text))
+            .await;
+        bot.receive(Message::new("#ops", "bob", "", MessageKind::Online))
+            .await;
+        bot.receive(Message::new("#ops", "bob", "", MessageKind::Offline))
+            .await;
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![
+                ("#ops", "welcome").into(),
+                ("#ops", "online").into(),
+                ("#ops", "gone").into(),
+                ("#ops", "offline").into(),
+            ]
+        );
+    }
 }
@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{migrate, migrate_v1, BrainStore, Data, VERSION};
+
+/// Brain as a json file.
+///
+/// Saving writes a temporary file next to it and renames it over the old
+/// one, so a crash leaves either the old or the new brain. Older versions,
+/// the bincode files of version 0 too, are migrated on load and rewritten on
+/// the next save.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: &str) -> Self {
+        FileStore { path: path.into() }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Saved<D> {
+    version: u32,
+    data: D,
+}
+
+impl BrainStore for FileStore {
+    fn load(&self) -> Result<Data> {
+        let buf = match fs::read(&self.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Data::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if buf.is_empty() {
+            return Ok(Data::new());
+        }
+        let saved: Saved<serde_json::Value> = match serde_json::from_slice(&buf) {
+            Ok(saved) => saved,
+            // version 0 is a bincode map
+            Err(e) => match bincode::deserialize::<HashMap<String, String>>(&buf) {
+                Ok(legacy) => {
+                    log::info!("brain {} migrated from version 0", self.path.display());
+                    return Ok(migrate(legacy));
+                }
+                Err(_) => return Err(e.into()),
+            },
+        };
+        Ok(match saved.version {
+            1 => migrate_v1(serde_json::from_value(saved.data)?),
+            VERSION => serde_json::from_value(saved.data)?,
+            v => bail!("brain version {v} is not supported"),
+        })
+    }
+
+    fn save(&self, data: &Data) -> Result<()> {
+        let data = serde_json::to_vec(&Saved {
+            version: VERSION,
+            data,
+        })?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut f = File::create(&tmp)?;
+        f.write_all(&data)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::brain::Entry;
+
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = env::temp_dir().join(format!("hongbot-brain-{}.dat", process::id()));
+        let store = FileStore::new(path.to_str().unwrap());
+        // missing file is an empty brain
+        assert!(store.load().unwrap().is_empty());
+
+        let data = Data::from([(
+            "karma".to_string(),
+            HashMap::from([("alice".to_string(), Entry::new(3.into()))]),
+        )]);
+        store.save(&data).unwrap();
+        assert_eq!(store.load().unwrap(), data);
+        store.save(&Data::new()).unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        let legacy = HashMap::from([("foo".to_string(), "bar".to_string())]);
+        fs::write(&path, bincode::serialize(&legacy).unwrap()).unwrap();
+        assert_eq!(store.load().unwrap(), migrate(legacy));
+
+        fs::write(&path, br#"{"version":1,"data":{"karma":{"alice":3}}}"#).unwrap();
+        assert_eq!(store.load().unwrap(), data);
+
+        fs::write(&path, br#"{"version":99,"data":{}}"#).unwrap();
+        assert!(store.load().is_err());
+        fs::write(&path, b"garbage").unwrap();
+        assert!(store.load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
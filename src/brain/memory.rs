@@ -0,0 +1,22 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use super::{BrainStore, Data};
+
+/// Brain kept in memory only, it is gone when the bot exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    data: Mutex<Data>,
+}
+
+impl BrainStore for MemoryStore {
+    fn load(&self) -> Result<Data> {
+        Ok(self.data.lock().unwrap().clone())
+    }
+
+    fn save(&self, data: &Data) -> Result<()> {
+        *self.data.lock().unwrap() = data.clone();
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{action::FACTOIDS, config::BrainConfig};
+
+use self::{file::FileStore, memory::MemoryStore, sqlite::SqliteStore};
+
+pub mod file;
+pub mod memory;
+pub mod sqlite;
+
+const BRAIN_FILE: &str = "state.dat";
+const BRAIN_DB: &str = "brain.db";
+
+/// version of the saved brain, stores migrate older ones on load
+pub const VERSION: u32 = 2;
+/// namespace of `Brain::get` and `Bot::get`
+pub const DEFAULT: &str = "default";
+
+/// namespace to key to entry
+pub type Data = HashMap<String, HashMap<String, Entry>>;
+
+/// value with the time it expires at, if any
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<SystemTime>,
+}
+
+impl Entry {
+    pub fn new(value: Value) -> Self {
+        Entry {
+            value,
+            expires: None,
+        }
+    }
+
+    pub fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|t| t <= now)
+    }
+}
+
+/// where the brain is persisted, see `Bot::save`
+pub trait BrainStore: Send + Sync {
+    /// everything saved, empty if nothing was saved yet
+    fn load(&self) -> Result<Data>;
+    /// replaces what was saved
+    fn save(&self, data: &Data) -> Result<()>;
+}
+
+/// version 0 brain, flat string keys and values
+///
+/// Only the factoids used the brain then, the keys move to their namespace.
+pub fn migrate(legacy: HashMap<String, String>) -> Data {
+    let mut data = HashMap::new();
+    if !legacy.is_empty() {
+        let values = legacy.into_iter().map(|(k, v)| (k, Value::String(v)));
+        data.insert(FACTOIDS.to_string(), values.collect());
+    }
+    migrate_v1(data)
+}
+
+/// version 1 brain, values without expiry
+pub fn migrate_v1(data: HashMap<String, HashMap<String, Value>>) -> Data {
+    data.into_iter()
+        .map(|(ns, keys)| {
+            let keys = keys.into_iter().map(|(k, v)| (k, Entry::new(v)));
+            (ns, keys.collect())
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreType {
+    #[default]
+    File,
+    Sqlite,
+    Memory,
+}
+
+impl StoreType {
+    /// store configured by `config`, the path defaults per type
+    pub fn build(&self, config: &BrainConfig) -> Result<Box<dyn BrainStore>> {
+        let path = config.path.as_deref();
+        Ok(match self {
+            StoreType::File => Box::new(FileStore::new(path.unwrap_or(BRAIN_FILE))),
+            StoreType::Sqlite => Box::new(SqliteStore::open(path.unwrap_or(BRAIN_DB))?),
+            StoreType::Memory => Box::new(MemoryStore::default()),
+        })
+    }
+}
+
+/// Store of the bot, values are serde values under a namespace per script.
+///
+/// Cloning gives another handle to the same store, so handlers and their
+/// background tasks can read and write it through `&Bot`. Expired keys read
+/// as missing and are removed by `purge`, which the bot runs periodically.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use hongbot_rs::brain::Brain;
+///
+/// let brain = Brain::default();
+/// let karma = brain.namespace("karma");
+/// let karma = karma.update("alice", |n: Option<i64>| Some(n.unwrap_or(0) + 1));
+/// assert_eq!(karma.unwrap(), Some(1));
+///
+/// let cooldown = brain.namespace("cooldown");
+/// cooldown.set_ttl("alice", &true, Duration::from_secs(60)).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Brain {
+    data: Arc<RwLock<Data>>,
+}
+
+impl Brain {
+    pub fn new(data: Data) -> Self {
+        Brain {
+            data: Arc::new(RwLock::new(data)),
+        }
+    }
+
+    /// keys of a script, kept apart from those of other scripts
+    pub fn namespace(&self, name: &str) -> Namespace {
+        Namespace {
+            data: self.data.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    /// value in the `DEFAULT` namespace
+    pub fn get<T: DeserializeOwned>(&self, k: &str) -> Option<T> {
+        self.namespace(DEFAULT).get(k)
+    }
+
+    pub fn set<T: Serialize>(&self, k: &str, v: &T) -> serde_json::Result<()> {
+        self.namespace(DEFAULT).set(k, v)
+    }
+
+    pub fn remove(&self, k: &str) -> Option<Value> {
+        self.namespace(DEFAULT).remove(k)
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.namespace(DEFAULT).keys()
+    }
+
+    /// removes the expired keys, returns how many
+    pub fn purge(&self) -> usize {
+        let now = SystemTime::now();
+        let mut purged = 0;
+        self.data.write().unwrap().retain(|_, keys| {
+            let len = keys.len();
+            keys.retain(|_, entry| !entry.expired(now));
+            purged += len - keys.len();
+            !keys.is_empty()
+        });
+        purged
+    }
+
+    /// copy of everything not expired, e.g. to persist it
+    pub fn snapshot(&self) -> Data {
+        let mut data = self.data.read().unwrap().clone();
+        let now = SystemTime::now();
+        data.retain(|_, keys| {
+            keys.retain(|_, entry| !entry.expired(now));
+            !keys.is_empty()
+        });
+        data
+    }
+}
+
+/// handle to the keys of one namespace, see `Brain::namespace`
+#[derive(Clone, Debug)]
+pub struct Namespace {
+    data: Arc<RwLock<Data>>,
+    name: String,
+}
+
+impl Namespace {
+    /// None if missing, expired or not a `T`
+    pub fn get<T: DeserializeOwned>(&self, k: &str) -> Option<T> {
+        let v = self.live(k, |entry| entry.value.clone())?;
+        match serde_json::from_value(v) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::warn!("brain {}/{}: {e}", self.name, k);
+                None
+            }
+        }
+    }
+
+    /// fails if `v` has no json representation, e.g. a map with non-string keys
+    pub fn set<T: Serialize>(&self, k: &str, v: &T) -> serde_json::Result<()> {
+        self.insert(k, Entry::new(serde_json::to_value(v)?));
+        Ok(())
+    }
+
+    /// like `set`, but the key expires after `ttl`
+    pub fn set_ttl<T: Serialize>(&self, k: &str, v: &T, ttl: Duration) -> serde_json::Result<()> {
+        self.insert(
+            k,
+            Entry {
+                value: serde_json::to_value(v)?,
+                expires: Some(SystemTime::now() + ttl),
+            },
+        );
+        Ok(())
+    }
+
+    /// time left until the key expires, None if missing or without expiry
+    pub fn ttl(&self, k: &str) -> Option<Duration> {
+        let expires = self.live(k, |entry| entry.expires)??;
+        Some(
+            expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// changes when an existing key expires, None keeps it forever, false if
+    /// the key is missing
+    pub fn expire(&self, k: &str, ttl: Option<Duration>) -> bool {
+        let now = SystemTime::now();
+        let mut data = self.data.write().unwrap();
+        match data.get_mut(&self.name).and_then(|keys| keys.get_mut(k)) {
+            Some(entry) if !entry.expired(now) => {
+                entry.expires = ttl.map(|ttl| now + ttl);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn remove(&self, k: &str) -> Option<Value> {
+        let mut data = self.data.write().unwrap();
+        let keys = data.get_mut(&self.name)?;
+        let entry = keys.remove(k);
+        if keys.is_empty() {
+            data.remove(&self.name);
+        }
+        entry
+            .filter(|entry| !entry.expired(SystemTime::now()))
+            .map(|entry| entry.value)
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        match self.data.read().unwrap().get(&self.name) {
+            Some(keys) => keys
+                .iter()
+                .filter(|(_, entry)| !entry.expired(now))
+                .map(|(k, _)| k.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Replaces the value with `f` of the current one, None removes the key.
+    ///
+    /// `f` runs without the brain locked, so it may use the brain itself. If
+    /// another update changes the value meanwhile, `f` runs again with the
+    /// new one. The expiry of the key is kept. Returns the new value, fails
+    /// if the current one is not a `T`.
+    pub fn update<T, F>(&self, k: &str, mut f: F) -> serde_json::Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnMut(Option<T>) -> Option<T>,
+    {
+        loop {
+            let current = self.live(k, |entry| entry.value.clone());
+            let new = f(current.clone().map(serde_json::from_value).transpose()?);
+            let value = new.as_ref().map(serde_json::to_value).transpose()?;
+            if self.swap(k, current.as_ref(), value) {
+                return Ok(new);
+            }
+        }
+    }
+
+    /// sets `new` only if the value is still `current`, None being missing,
+    /// and tells if it did
+    pub fn compare_and_swap<T: Serialize>(
+        &self,
+        k: &str,
+        current: Option<&T>,
+        new: Option<&T>,
+    ) -> serde_json::Result<bool> {
+        let current = current.map(serde_json::to_value).transpose()?;
+        let new = new.map(serde_json::to_value).transpose()?;
+        Ok(self.swap(k, current.as_ref(), new))
+    }
+
+    /// `compare_and_swap` of json values, keeps the expiry of the key
+    fn swap(&self, k: &str, current: Option<&Value>, new: Option<Value>) -> bool {
+        let now = SystemTime::now();
+        let mut data = self.data.write().unwrap();
+        let keys = data.entry(self.name.clone()).or_default();
+        let entry = keys.get(k).filter(|entry| !entry.expired(now));
+        if entry.map(|entry| &entry.value) != current {
+            return false;
+        }
+        let expires = entry.and_then(|entry| entry.expires);
+        match new {
+            Some(value) => {
+                keys.insert(k.to_string(), Entry { value, expires });
+            }
+            None => {
+                keys.remove(k);
+            }
+        }
+        if keys.is_empty() {
+            data.remove(&self.name);
+        }
+        true
+    }
+
+    fn insert(&self, k: &str, entry: Entry) {
+        self.data
+            .write()
+            .unwrap()
+            .entry(self.name.clone())
+            .or_default()
+            .insert(k.to_string(), entry);
+    }
+
+    /// `f` of the entry unless missing or expired
+    fn live<R>(&self, k: &str, f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        let data = self.data.read().unwrap();
+        let entry = data.get(&self.name)?.get(k)?;
+        (!entry.expired(SystemTime::now())).then(|| f(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shared() {
+        let brain = Brain::default();
+        let handle = brain.clone();
+        tokio::spawn(async move {
+            handle.set("foo", &"bar").unwrap();
+        })
+        .await
+        .unwrap();
+        assert_eq!(brain.get::<String>("foo").as_deref(), Some("bar"));
+        assert_eq!(brain.keys(), vec!["foo".to_string()]);
+        assert_eq!(brain.remove("foo"), Some(Value::from("bar")));
+        assert!(brain.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let brain = Brain::default();
+        let karma = brain.namespace("karma");
+        let votes = brain.namespace("votes");
+        karma.set("alice", &3).unwrap();
+        votes
+            .set("alice", &HashMap::from([("lunch", vec!["pizza"])]))
+            .unwrap();
+
+        assert_eq!(karma.get::<i64>("alice"), Some(3));
+        // wrong type
+        assert_eq!(karma.get::<String>("alice"), None);
+        let ballot = votes.get::<HashMap<String, Vec<String>>>("alice").unwrap();
+        assert_eq!(ballot["lunch"], vec!["pizza".to_string()]);
+        assert!(brain.get::<i64>("alice").is_none());
+        assert!(karma.remove("alice").is_some());
+        assert_eq!(brain.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let brain = Brain::default();
+        let cooldown = brain.namespace("cooldown");
+        cooldown
+            .set_ttl("alice", &true, Duration::from_secs(60))
+            .unwrap();
+        cooldown.set("bob", &true).unwrap();
+        assert!(cooldown.ttl("alice").unwrap() <= Duration::from_secs(60));
+        assert_eq!(cooldown.ttl("bob"), None);
+
+        assert!(cooldown.expire("alice", Some(Duration::ZERO)));
+        assert_eq!(cooldown.get::<bool>("alice"), None);
+        assert_eq!(cooldown.keys(), vec!["bob".to_string()]);
+        assert!(!cooldown.expire("alice", None));
+        assert_eq!(brain.purge(), 1);
+        assert_eq!(brain.purge(), 0);
+
+        cooldown.expire("bob", Some(Duration::ZERO));
+        assert!(brain.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let brain = Brain::default();
+        let karma = brain.namespace("karma");
+        karma.set_ttl("alice", &1, Duration::from_secs(60)).unwrap();
+
+        let incr = |n: Option<i64>| Some(n.unwrap_or(0) + 1);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let karma = karma.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        karma.update("alice", incr).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(karma.get::<i64>("alice"), Some(801));
+        // expiry is kept
+        assert!(karma.ttl("alice").is_some());
+        assert!(karma.update("alice", |_: Option<String>| None).is_err());
+        assert_eq!(karma.update("alice", |_: Option<i64>| None).unwrap(), None);
+        assert!(brain.snapshot().is_empty());
+
+        assert!(karma.compare_and_swap("bob", None, Some(&1)).unwrap());
+        assert!(!karma.compare_and_swap("bob", None, Some(&2)).unwrap());
+        assert!(karma.compare_and_swap("bob", Some(&1), None).unwrap());
+        assert_eq!(karma.get::<i64>("bob"), None);
+
+        // the closure may use the brain, and a panic in it poisons nothing
+        karma.set("alice", &3).unwrap();
+        let total = karma.update("total", |_: Option<i64>| karma.get("alice"));
+        assert_eq!(total.unwrap(), Some(3));
+        let panicked = std::panic::catch_unwind(|| {
+            karma.update("carol", |_: Option<i64>| panic!("oops")).ok();
+        });
+        assert!(panicked.is_err());
+        karma.set("carol", &1).unwrap();
+        assert_eq!(karma.get::<i64>("carol"), Some(1));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let legacy = HashMap::from([("foo".to_string(), "bar".to_string())]);
+        let brain = Brain::new(migrate(legacy));
+        let factoids = brain.namespace(FACTOIDS);
+        assert_eq!(factoids.get::<String>("foo").as_deref(), Some("bar"));
+        assert!(migrate(HashMap::new()).is_empty());
+    }
+}
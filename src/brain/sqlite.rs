@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection};
+
+use super::{migrate, BrainStore, Data, Entry, VERSION};
+
+/// Brain in a SQLite database, one row per key with the value as json and
+/// the expiry in unix milliseconds.
+///
+/// The schema version is kept in `user_version`, databases of version 0
+/// are migrated when opened.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// opens or creates the database at path, `:memory:` for a private one
+    pub fn open(path: &str) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => upgrade(&mut conn)?,
+            1 => {
+                let tx = conn.transaction()?;
+                tx.execute("ALTER TABLE brain ADD COLUMN expires INTEGER", [])?;
+                tx.pragma_update(None, "user_version", VERSION)?;
+                tx.commit()?;
+            }
+            VERSION => (),
+            v => bail!("brain version {v} is not supported"),
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+/// creates the tables, moving the rows of a version 0 database over
+fn upgrade(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    let mut legacy = HashMap::new();
+    let exists: bool = tx.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'brain'",
+        [],
+        |row| row.get(0),
+    )?;
+    if exists {
+        let mut stmt = tx.prepare("SELECT key, value FROM brain")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        legacy = rows.collect::<rusqlite::Result<_>>()?;
+        drop(stmt);
+        tx.execute("DROP TABLE brain", [])?;
+    }
+    tx.execute(
+        "CREATE TABLE brain (
+            namespace TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            expires INTEGER,
+            PRIMARY KEY (namespace, key)
+        )",
+        [],
+    )?;
+    insert(&tx, &migrate(legacy))?;
+    tx.pragma_update(None, "user_version", VERSION)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn insert(conn: &Connection, data: &Data) -> Result<()> {
+    let mut stmt =
+        conn.prepare("INSERT INTO brain (namespace, key, value, expires) VALUES (?1, ?2, ?3, ?4)")?;
+    for (namespace, keys) in data {
+        for (k, entry) in keys {
+            let value = serde_json::to_string(&entry.value)?;
+            let expires = match entry.expires {
+                Some(t) => Some(t.duration_since(UNIX_EPOCH)?.as_millis() as i64),
+                None => None,
+            };
+            stmt.execute(params![namespace, k, value, expires])?;
+        }
+    }
+    Ok(())
+}
+
+impl BrainStore for SqliteStore {
+    fn load(&self) -> Result<Data> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT namespace, key, value, expires FROM brain")?;
+        let mut rows = stmt.query([])?;
+        let mut data = Data::new();
+        while let Some(row) = rows.next()? {
+            let value: String = row.get(2)?;
+            let expires: Option<i64> = row.get(3)?;
+            let entry = Entry {
+                value: serde_json::from_str(&value)?,
+                expires: expires.map(|ms| UNIX_EPOCH + Duration::from_millis(ms as u64)),
+            };
+            data.entry(row.get(0)?)
+                .or_default()
+                .insert(row.get(1)?, entry);
+        }
+        Ok(data)
+    }
+
+    fn save(&self, data: &Data) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM brain", [])?;
+        insert(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn test_sqlite_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        let cooldown = Entry {
+            value: true.into(),
+            expires: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        };
+        let data = Data::from([
+            (
+                "cooldown".to_string(),
+                HashMap::from([("alice".to_string(), cooldown)]),
+            ),
+            (
+                "factoids".to_string(),
+                HashMap::from([("foo".to_string(), Entry::new("bar".into()))]),
+            ),
+        ]);
+        store.save(&data).unwrap();
+        assert_eq!(store.load().unwrap(), data);
+
+        store.save(&Data::new()).unwrap();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_migrate() {
+        let path = env::temp_dir().join(format!("hongbot-brain-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let conn = Connection::open(path).unwrap();
+            conn.execute(
+                "CREATE TABLE brain (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+                [],
+            )
+            .unwrap();
+            conn.execute("INSERT INTO brain VALUES ('foo', 'bar')", [])
+                .unwrap();
+        }
+        let legacy = HashMap::from([("foo".to_string(), "bar".to_string())]);
+        assert_eq!(
+            SqliteStore::open(path).unwrap().load().unwrap(),
+            migrate(legacy.clone())
+        );
+        // already migrated
+        assert_eq!(
+            SqliteStore::open(path).unwrap().load().unwrap(),
+            migrate(legacy)
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::{sync::mpsc::unbounded_channel, task::JoinHandle};
+
+use crate::{
+    bot::{Message, MessageKind},
+    config::{BridgeConfig, Config, Endpoint},
+    server::Server,
+};
+
+/// name of the bot's own connection
+pub const MAIN: &str = "main";
+// relayed lines remembered to drop echoes
+const RELAYED: usize = 64;
+
+/// Relays messages between channel pairs, see `BridgeConfig`.
+///
+/// Ends are on named connections, any number of them may use the same
+/// adapter. `main` is shared with the bot and the others are connected by
+/// the bridge. Messages are forwarded only to the direct peers of their
+/// channel, as `<nick> message`.
+pub struct Bridge {
+    name: String,
+    servers: HashMap<String, Arc<dyn Server>>,
+    links: Vec<Link>,
+    /// lines sent lately, an adapter echoing them back must not start a loop
+    relayed: Mutex<VecDeque<(End, String)>>,
+}
+
+/// connection name and channel
+type End = (String, String);
+
+struct Link {
+    left: End,
+    right: End,
+    joins: bool,
+}
+
+impl Link {
+    /// the other end if the message came from one of ours
+    fn peer(&self, connection: &str, channel: &str) -> Option<&End> {
+        let is = |end: &End| end.0 == connection && end.1.eq_ignore_ascii_case(channel);
+        if is(&self.left) {
+            Some(&self.right)
+        } else if is(&self.right) {
+            Some(&self.left)
+        } else {
+            None
+        }
+    }
+}
+
+impl Bridge {
+    /// connections other than `server` are built from `config.connections`
+    pub fn new(server: Arc<dyn Server>, config: &Config) -> Self {
+        let bridges = config.bridge.clone().unwrap_or_default();
+        let connections = config.connections.clone().unwrap_or_default();
+        let mut servers = HashMap::from([(MAIN.to_string(), server)]);
+        for bridge in &bridges {
+            for end in [&bridge.left, &bridge.right] {
+                let Some(name) = &end.connection else {
+                    continue;
+                };
+                if servers.contains_key(name) {
+                    continue;
+                }
+                match connections.get(name) {
+                    Some(connection) => {
+                        servers.insert(name.clone(), connection.build(&config.name));
+                    }
+                    None => log::error!("bridge connection {name} is not configured"),
+                }
+            }
+        }
+        Bridge::with_servers(config.name.clone(), servers, &bridges)
+    }
+
+    /// bridge over the given connections by name, `main` is the one of the bot
+    pub fn with_servers(
+        name: String,
+        servers: HashMap<String, Arc<dyn Server>>,
+        bridges: &[BridgeConfig],
+    ) -> Self {
+        let end = |e: &Endpoint| {
+            let connection = e.connection.as_deref().unwrap_or(MAIN);
+            (connection.to_string(), e.channel.clone())
+        };
+        let links = bridges
+            .iter()
+            .map(|b| Link {
+                left: end(&b.left),
+                right: end(&b.right),
+                joins: b.joins.unwrap_or(false),
+            })
+            .collect();
+        Bridge {
+            name,
+            servers,
+            links,
+            relayed: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// connects the servers other than the main one, their messages are
+    /// only relayed and never reach the bot handlers
+    pub async fn connect(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut handles = Vec::new();
+        for (name, server) in &self.servers {
+            if name == MAIN {
+                continue;
+            }
+            let (tx, mut rx) = unbounded_channel::<Message>();
+            match server.connect(tx).await {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    log::error!("bridge connect {name} fail: {e}");
+                    continue;
+                }
+            }
+            let bridge = self.clone();
+            let name = name.clone();
+            handles.push(tokio::spawn(async move {
+                while let Some(mut msg) = rx.recv().await {
+                    msg.connection = Some(name.clone());
+                    bridge.relay(&msg).await;
+                }
+            }));
+        }
+        handles
+    }
+
+    pub async fn disconnect(&self) {
+        for (name, server) in &self.servers {
+            if name != MAIN {
+                server.disconnect().await;
+            }
+        }
+    }
+
+    /// forwards a message to the peers of its channel, it came in on `main`
+    /// unless `Message::connection` says otherwise
+    pub async fn relay(&self, msg: &Message) {
+        let from = msg.connection.as_deref().unwrap_or(MAIN);
+        if msg.nick == self.name || self.echoed(from, msg) {
+            return;
+        }
+
+        for link in &self.links {
+            let Some(to) = link.peer(from, &msg.channel) else {
+                continue;
+            };
+            let line = match msg.kind {
+                MessageKind::Text => format!("<{}> {}", msg.nick, msg.message),
+                MessageKind::Emote => format!("{} {}", msg.nick, msg.message),
+                MessageKind::Join if link.joins => {
+                    format!("{} has joined {}", msg.nick, msg.channel)
+                }
+                MessageKind::Part if link.joins => {
+                    format!("{} has left {}", msg.nick, msg.channel)
+                }
+                _ => continue,
+            };
+            let Some(target) = self.servers.get(&to.0) else {
+                continue;
+            };
+
+            {
+                let mut relayed = self.relayed.lock().unwrap();
+                relayed.push_back((to.clone(), line.clone()));
+                if relayed.len() > RELAYED {
+                    relayed.pop_front();
+                }
+            }
+            let result = match msg.kind {
+                MessageKind::Emote => target.emote(&to.1, &line).await,
+                MessageKind::Join | MessageKind::Part => target.notice(&to.1, &line).await,
+                _ => target.send(&to.1, &line).await,
+            };
+            if let Err(e) = result {
+                log::error!("relay to {} on {} fail: {e}", to.1, to.0);
+            }
+        }
+    }
+
+    /// true if the message is a line we relayed coming back
+    fn echoed(&self, connection: &str, msg: &Message) -> bool {
+        let mut relayed = self.relayed.lock().unwrap();
+        let position = relayed.iter().position(|((name, channel), line)| {
+            name == connection && channel.eq_ignore_ascii_case(&msg.channel) && *line == msg.message
+        });
+        match position {
+            Some(i) => relayed.remove(i).is_some(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use crate::{
+        bot::{Bot, ServerType},
+        config::ConnectionConfig,
+        server::test::{Sent, TestServer},
+    };
+
+    use super::*;
+
+    fn bridge(irc: &TestServer, slack: &TestServer, joins: bool) -> Bridge {
+        let servers: HashMap<String, Arc<dyn Server>> = HashMap::from([
+            (MAIN.to_string(), Arc::new(irc.clone()) as Arc<dyn Server>),
+            (
+                "slack".to_string(),
+                Arc::new(slack.clone()) as Arc<dyn Server>,
+            ),
+        ]);
+        let bridges = [BridgeConfig {
+            left: Endpoint {
+                connection: None,
+                channel: "#ops".to_string(),
+            },
+            right: Endpoint {
+                connection: Some("slack".to_string()),
+                channel: "C01".to_string(),
+            },
+            joins: Some(joins),
+        }];
+        Bridge::with_servers("hongbot".to_string(), servers, &bridges)
+    }
+
+    /// message received on the named connection
+    fn on(connection: &str, channel: &str, nick: &str, text: &str, kind: MessageKind) -> Message {
+        let mut msg = Message::new(channel, nick, text, kind);
+        msg.connection = Some(connection.to_string());
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (irc, slack) = (TestServer::new(), TestServer::new());
+        let bridge = bridge(&irc, &slack, false);
+
+        bridge
+            .relay(&Message::new("#OPS", "alice", "hi", MessageKind::Text))
+            .await;
+        bridge
+            .relay(&on("slack", "C01", "bob", "waves", MessageKind::Emote))
+            .await;
+        // unlinked channel, own message and joins when not mapped
+        bridge
+            .relay(&Message::new("#foo", "alice", "hi", MessageKind::Text))
+            .await;
+        bridge
+            .relay(&Message::new("#ops", "hongbot", "hi", MessageKind::Text))
+            .await;
+        bridge
+            .relay(&Message::new("#ops", "carol", "", MessageKind::Join))
+            .await;
+        // same channel on a connection without a link
+        bridge
+            .relay(&on("slack", "#ops", "dave", "hi", MessageKind::Text))
+            .await;
+
+        let bot = Bot::with_server("hongbot".to_string(), Arc::new(irc.clone()));
+        assert_eq!(slack.drain(&bot).await, vec![("C01", "<alice> hi").into()]);
+        assert_eq!(
+            irc.drain(&bot).await,
+            vec![Sent::Emote {
+                channel: "#ops".to_string(),
+                action: "bob waves".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_echo_and_joins() {
+        let (irc, slack) = (TestServer::new(), TestServer::new());
+        let bridge = bridge(&irc, &slack, true);
+
+        bridge
+            .relay(&Message::new("#ops", "alice", "hi", MessageKind::Text))
+            .await;
+        // an adapter echoing the relayed line back is ignored once
+        let echo = on("slack", "C01", "someone", "<alice> hi", MessageKind::Text);
+        bridge.relay(&echo).await;
+        bridge
+            .relay(&Message::new("#ops", "carol", "", MessageKind::Join))
+            .await;
+        bridge
+            .relay(&Message::new("#ops", "carol", "bye", MessageKind::Part))
+            .await;
+
+        let bot = Bot::with_server("hongbot".to_string(), Arc::new(irc.clone()));
+        assert!(irc.drain(&bot).await.is_empty());
+        assert_eq!(
+            slack.drain(&bot).await,
+            vec![
+                ("C01", "<alice> hi").into(),
+                Sent::Notice {
+                    channel: "C01".to_string(),
+                    message: "carol has joined #ops".to_string(),
+                },
+                Sent::Notice {
+                    channel: "C01".to_string(),
+                    message: "carol has left #ops".to_string(),
+                },
+            ]
+        );
+
+        bridge.relay(&echo).await;
+        assert_eq!(
+            irc.drain(&bot).await,
+            vec![("#ops", "<someone> <alice> hi").into()]
+        );
+    }
+
+    #[test]
+    fn test_connections() {
+        let toml = r##"
+            name = "hongbot"
+            server = "shell"
+            scripts = []
+
+            [connections.efnet]
+            type = "irc"
+            nick = "hongbot"
+            addr = "irc.efnet.org:6667"
+            channels = ["#ops"]
+
+            [connections.libera]
+            type = "irc"
+            nick = "hongbot"
+            addr = "irc.libera.chat:6667"
+            channels = ["#ops"]
+
+            [[bridge]]
+            left = { connection = "efnet", channel = "#ops" }
+            right = { connection = "libera", channel = "#ops" }
+        "##;
+        let config: Config = config::Config::builder()
+            .add_source(config::File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let connections = config.connections.as_ref().unwrap();
+        assert!(matches!(connections["efnet"], ConnectionConfig::Irc(_)));
+        assert!(matches!(connections["libera"], ConnectionConfig::Irc(_)));
+
+        let shell = ServerType::Shell.build(&config);
+        let bridge = Bridge::new(shell, &config);
+        let mut names = bridge.servers.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["efnet", "libera", MAIN]);
+        assert_eq!(
+            bridge.links[0].left,
+            ("efnet".to_string(), "#ops".to_string())
+        );
+        assert_eq!(
+            bridge.links[0].right,
+            ("libera".to_string(), "#ops".to_string())
+        );
+    }
+}
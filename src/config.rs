@@ -1,16 +1,66 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
 use config::ConfigError;
 use serde::Deserialize;
 
-use crate::bot::ServerType;
+use crate::{bot::ServerType, brain::StoreType, http::INDEX};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub name: String,
     pub server: ServerType,
     pub scripts: Vec<String>,
+    /// address of the builtin http server, it is started when this is set or
+    /// the adapter serves routes, e.g. the webhook, then on 127.0.0.1:8080
+    pub http_addr: Option<String>,
+    pub brain: Option<BrainConfig>,
+    pub shell: Option<ShellConfig>,
     pub irc: Option<IrcConfig>,
+    pub slack: Option<SlackConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub telegram: Option<TelegramConfig>,
+    pub mattermost: Option<MattermostConfig>,
+    pub xmpp: Option<XmppConfig>,
+    pub webhook: Option<WebhookConfig>,
+    /// more adapters by name, each `[connections.<name>]` table has a `type`
+    /// and the fields of that adapter's section, `main` is the bot's own
+    pub connections: Option<HashMap<String, ConnectionConfig>>,
+    /// channel pairs to relay between
+    pub bridge: Option<Vec<BridgeConfig>>,
+}
+
+/// adapter of a named connection, tagged by its `type`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ConnectionConfig {
+    Shell(ShellConfig),
+    Irc(IrcConfig),
+    Slack(SlackConfig),
+    Discord(DiscordConfig),
+    Matrix(MatrixConfig),
+    Telegram(TelegramConfig),
+    Mattermost(MattermostConfig),
+    Xmpp(XmppConfig),
+    Webhook(WebhookConfig),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BrainConfig {
+    /// defaults to file
+    pub store: Option<StoreType>,
+    /// file or database, defaults to state.dat for file and brain.db for sqlite
+    pub path: Option<String>,
+    /// seconds between saves while running, 0 saves on shutdown only,
+    /// defaults to 300
+    pub autosave: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ShellConfig {
+    /// file to read commands from in batch mode, batch mode is also used
+    /// when stdin is not a terminal
+    pub input: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -21,13 +71,147 @@ pub struct IrcConfig {
     pub realname: Option<String>,
     pub addr: String,
     pub channels: Vec<String>,
+    /// nicks to watch for presence
+    pub monitor: Option<Vec<String>>,
+    /// ISON polling interval in seconds, used when the server lacks MONITOR
+    pub ison_interval: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SlackConfig {
+    /// app-level token (xapp-) for Socket Mode
+    pub app_token: String,
+    /// bot token (xoxb-) for Web API
+    pub bot_token: String,
+    /// Web API base url, defaults to https://slack.com/api
+    pub api_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiscordConfig {
+    /// bot token
+    pub token: String,
+    /// REST api base url, defaults to https://discord.com/api/v10
+    pub api_url: Option<String>,
+    /// gateway url, fetched from `GET /gateway/bot` if not set
+    pub gateway_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MatrixConfig {
+    /// homeserver base url, e.g. https://matrix.example.org
+    pub homeserver: String,
+    pub access_token: String,
+    /// room ids or aliases to join on connect
+    pub rooms: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TelegramConfig {
+    /// bot token from @BotFather
+    pub token: String,
+    /// Bot API base url, defaults to https://api.telegram.org
+    pub api_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MattermostConfig {
+    /// server base url, e.g. https://chat.example.com
+    pub url: String,
+    /// personal access token
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct XmppConfig {
+    /// bot jid, e.g. hongbot@example.org
+    pub jid: String,
+    pub password: String,
+    /// server address, defaults to the jid domain on port 5222
+    pub addr: Option<String>,
+    /// multi-user chat rooms to join, e.g. ops@conference.example.org
+    pub rooms: Vec<String>,
+    /// nick in rooms, defaults to the bot name
+    pub nick: Option<String>,
+    /// send the password without TLS when the server has no STARTTLS,
+    /// e.g. a local test server, defaults to false
+    pub plaintext: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// path receiving incoming messages on the builtin http server, defaults to /webhook,
+    /// starts with / but is not / itself and has no : or * segments
+    pub path: Option<String>,
+    /// url bot output is POSTed to
+    pub url: String,
+    /// shared secret, required as `Authorization: Bearer <token>` on incoming
+    /// requests and sent with outgoing ones
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BridgeConfig {
+    pub left: Endpoint,
+    pub right: Endpoint,
+    /// relay joins and parts too, defaults to false
+    pub joins: Option<bool>,
+}
+
+/// channel on one of the connections
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Endpoint {
+    /// name in `connections`, defaults to `main`, the bot's own
+    pub connection: Option<String>,
+    pub channel: String,
 }
 
 impl Config {
     pub fn from(path: &Path) -> Result<Self, ConfigError> {
-        config::Config::builder()
+        let config: Config = config::Config::builder()
             .add_source(config::File::from(path))
             .build()?
-            .try_deserialize()
+            .try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// checks what deserializing can't, e.g. a path the http server would
+    /// panic on
+    fn validate(&self) -> Result<(), ConfigError> {
+        let connections = self.connections.iter().flat_map(HashMap::values);
+        let webhooks = connections.filter_map(|connection| match connection {
+            ConnectionConfig::Webhook(webhook) => Some(webhook),
+            _ => None,
+        });
+        self.webhook
+            .iter()
+            .chain(webhooks)
+            .try_for_each(WebhookConfig::validate)
+    }
+}
+
+impl WebhookConfig {
+    /// the path must be servable by the http server next to its builtin routes
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let error = if !path.starts_with('/') {
+            "must start with /"
+        } else if path == INDEX {
+            "is taken by the builtin index"
+        } else if path
+            .split('/')
+            .any(|s| s.starts_with(':') || s.starts_with('*'))
+        {
+            "must not have : or * segments"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigError::Message(format!(
+            "webhook path {:?} {}",
+            path, error
+        )))
     }
 }
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::seq::SliceRandom;
+use regex::Captures;
+
+use crate::{
+    bot::{Bot, Message, MessageKind},
+    brain::Brain,
+    server::{net, SendResult},
+};
+
+/// What a handler is called with: the bot, the matched message and the
+/// captures of the pattern, plus shortcuts to answer on the same channel.
+///
+/// ```
+/// use futures_util::future::BoxFuture;
+/// use hongbot_rs::context::Context;
+///
+/// fn ping<'a>(ctx: Context<'a>) -> BoxFuture<'a, ()> {
+///     Box::pin(async move {
+///         ctx.reply("pong").await.ok();
+///     })
+/// }
+/// ```
+pub struct Context<'a> {
+    pub bot: &'a Bot,
+    pub message: &'a Message,
+    /// set when the handler was registered with a regex
+    pub captures: Option<Captures<'a>>,
+    finished: &'a AtomicBool,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        bot: &'a Bot,
+        message: &'a Message,
+        captures: Option<Captures<'a>>,
+        finished: &'a AtomicBool,
+    ) -> Self {
+        Context {
+            bot,
+            message,
+            captures,
+            finished,
+        }
+    }
+
+    /// marks the message handled, handlers after this one are skipped
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn channel(&self) -> &'a str {
+        &self.message.channel
+    }
+
+    pub fn nick(&self) -> &'a str {
+        &self.message.nick
+    }
+
+    /// for presence events, true if the nick came online
+    pub fn online(&self) -> bool {
+        self.message.kind == MessageKind::Online
+    }
+
+    /// capture group by index, 0 is the whole match
+    pub fn group(&self, i: usize) -> Option<&'a str> {
+        self.captures.as_ref()?.get(i).map(|m| m.as_str())
+    }
+
+    /// named capture group, `(?P<name>...)`
+    pub fn named(&self, name: &str) -> Option<&'a str> {
+        self.captures.as_ref()?.name(name).map(|m| m.as_str())
+    }
+
+    /// message addressed to the sender
+    pub async fn reply(&self, message: &str) -> SendResult {
+        self.bot.reply(self.channel(), self.nick(), message).await
+    }
+
+    /// message to the channel
+    pub async fn send(&self, message: &str) -> SendResult {
+        self.bot.send(self.channel(), message).await
+    }
+
+    pub async fn emote(&self, action: &str) -> SendResult {
+        self.bot.emote(self.channel(), action).await
+    }
+
+    /// one of `choices`, None if empty
+    pub fn random<'c, T>(&self, choices: &'c [T]) -> Option<&'c T> {
+        choices.choose(&mut rand::thread_rng())
+    }
+
+    pub fn brain(&self) -> &'a Brain {
+        self.bot.brain()
+    }
+
+    /// shared http client, connections are pooled with the adapters,
+    /// requests belong in `Bot::spawn` so they don't hold up other messages
+    pub fn http(&self) -> reqwest::Client {
+        net::client().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::future::BoxFuture;
+
+    use crate::server::test::{Sent, TestServer};
+
+    use super::*;
+
+    fn roll(ctx: Context<'_>) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let sides = ctx.named("sides").unwrap_or("6");
+            let face = ctx.random(&["1", "2"]).unwrap();
+            ctx.reply(&format!("d{} {} {}", sides, ctx.group(0).unwrap(), face))
+                .await
+                .unwrap();
+            ctx.emote("rolls").await.unwrap();
+        })
+    }
+
+    #[tokio::test]
+    async fn test_context() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.respond(r"roll d(?P<sides>\d+)", roll).unwrap();
+
+        let msg = Message::new("#ops", "alice", "hongbot: roll d20", MessageKind::Text);
+        assert!(bot.receive(msg).await);
+        let sent = server.drain(&bot).await;
+        assert_eq!(sent.len(), 2);
+        let Sent::Reply { nick, message, .. } = &sent[0] else {
+            panic!("unexpected {:?}", sent[0]);
+        };
+        assert_eq!(nick, "alice");
+        assert!(message.starts_with("d20 hongbot: roll d20 "));
+    }
+}
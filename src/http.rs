@@ -1,46 +1,102 @@
-use std::{
-    io::Cursor,
-    sync::Arc,
-    thread::{self, JoinHandle},
-};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
-use tiny_http::{Request, Response, Server, StatusCode};
+pub use axum::{
+    body::Body,
+    extract::Request,
+    http::{Method, StatusCode},
+    response::Response,
+};
+use axum::{
+    response::IntoResponse,
+    routing::{get, MethodFilter, MethodRouter},
+    Router,
+};
+use futures_util::future::BoxFuture;
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+
+pub type Handler = Box<dyn Fn(Request) -> BoxFuture<'static, Response> + Send + Sync>;
+
+/// path of the builtin index, not available to `Server::routes`
+pub const INDEX: &str = "/";
+
+/// extra endpoint served next to the builtin ones, see `Server::routes`
+pub struct Route {
+    pub method: Method,
+    pub path: String,
+    pub handler: Handler,
+}
+
+/// running http server, see `serve`
+pub struct HttpServer {
+    pub addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
 
-pub fn serve(addr: &str) -> Result<(Arc<Server>, Vec<JoinHandle<()>>)> {
-    let server = Arc::new(Server::http(addr).unwrap());
+impl HttpServer {
+    /// graceful shutdown, waits for in-flight requests
+    pub async fn shutdown(self) {
+        self.shutdown.send(()).ok();
+        self.handle.await.ok();
+    }
+}
+
+pub async fn serve(addr: &str, routes: Vec<Route>) -> Result<HttpServer> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
     log::info!("Listening for connections on http://{}", addr);
-    const MAX_WORKERS: usize = 4;
-    let mut guards = Vec::with_capacity(MAX_WORKERS);
-    for _ in 0..MAX_WORKERS {
-        let server = server.clone();
-        let guard = thread::spawn(move || {
-            for req in server.incoming_requests() {
-                let path = req.url();
-                let method = req.method();
-                let resp = match method {
-                    tiny_http::Method::Get => match path {
-                        "/" => index(&req),
-                        _ => error_resp(404),
-                    },
-                    _ => error_resp(405),
-                };
-                req.respond(resp).unwrap();
-            }
-        });
-        guards.push(guard);
+
+    // routes on the same path share a method router
+    let mut paths: HashMap<String, MethodRouter> = HashMap::new();
+    for route in routes {
+        let filter = MethodFilter::try_from(route.method)?;
+        let handler = Arc::new(route.handler);
+        let method_router =
+            paths
+                .remove(&route.path)
+                .unwrap_or_default()
+                .on(filter, move |req: Request| {
+                    let handler = handler.clone();
+                    async move { handler(req).await }
+                });
+        paths.insert(route.path, method_router);
+    }
+    let mut router = Router::new().route(INDEX, get(index));
+    for (path, method_router) in paths {
+        router = router.route(&path, method_router);
     }
+    let router = router.fallback(|req: Request| async move {
+        match *req.method() {
+            Method::GET => error_resp(404),
+            _ => error_resp(405),
+        }
+    });
+
+    let (shutdown, rx) = oneshot::channel();
+    let handle = tokio::spawn(async move {
+        let server = axum::serve(listener, router).with_graceful_shutdown(async {
+            rx.await.ok();
+        });
+        if let Err(e) = server.await {
+            log::error!("http server fail: {e}");
+        }
+    });
 
-    Ok((server, guards))
+    Ok(HttpServer {
+        addr,
+        shutdown,
+        handle,
+    })
 }
 
-fn index(req: &Request) -> Response<Cursor<Vec<u8>>> {
-    log::trace!("{} {}", req.method(), req.url());
+async fn index(req: Request) -> Response {
+    log::trace!("{} {}", req.method(), req.uri());
     // do something
-    Response::from_string("OK")
+    "OK".into_response()
 }
 
-fn error_resp(code: u16) -> Response<Cursor<Vec<u8>>> {
-    let code = StatusCode(code);
-    Response::from_string(code.default_reason_phrase()).with_status_code(code)
+pub fn error_resp(code: u16) -> Response {
+    let code = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (code, code.canonical_reason().unwrap_or_default()).into_response()
 }
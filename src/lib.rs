@@ -1,5 +1,11 @@
 pub mod action;
 pub mod bot;
+pub mod brain;
+pub mod bridge;
 pub mod config;
+pub mod context;
 pub mod http;
+pub mod matcher;
+pub mod middleware;
 pub mod server;
+pub mod transcript;
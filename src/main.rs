@@ -2,7 +2,8 @@ use std::path::PathBuf;
 
 use hongbot_rs::{bot::Bot, config::Config};
 
-fn main() {
+#[tokio::main]
+async fn main() {
     // initialize
     dotenvy::dotenv().ok();
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
@@ -11,5 +12,8 @@ fn main() {
 
     let mut bot = Bot::new(config);
     bot.install_actions();
-    bot.run();
+    if let Err(e) = bot.run().await {
+        log::error!("{e:#}");
+        std::process::exit(1);
+    }
 }
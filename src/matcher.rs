@@ -0,0 +1,226 @@
+use regex::{Captures, Regex};
+
+use crate::bot::{Message, MessageKind};
+
+/// Decides which messages a handler gets, see `Bot::on`.
+///
+/// ```
+/// use hongbot_rs::{
+///     bot::MessageKind,
+///     matcher::{All, Channel, Kind},
+/// };
+///
+/// // joins on #ops
+/// let matcher = All(vec![Box::new(Channel::new("#ops")), Box::new(Kind(MessageKind::Join))]);
+/// ```
+pub trait Matcher: Send + Sync {
+    /// None if the message does not match
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>>;
+    /// used to warn about duplicates, None if it can't be told
+    fn describe(&self) -> Option<String> {
+        None
+    }
+}
+
+/// successful match, with the captures of a regex if there was one
+#[derive(Debug, Default)]
+pub struct Matched<'a> {
+    pub captures: Option<Captures<'a>>,
+}
+
+/// regex on the text of messages and emotes
+#[derive(Clone, Debug)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Pattern(Regex::new(pattern)?))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Matcher for Pattern {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        if !matches!(msg.kind, MessageKind::Text | MessageKind::Emote) {
+            return None;
+        }
+        let captures = self.0.captures(msg.trim())?;
+        Some(Matched {
+            captures: Some(captures),
+        })
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(self.0.as_str().to_string())
+    }
+}
+
+/// messages on the channel, case-insensitive
+#[derive(Clone, Debug)]
+pub struct Channel(String);
+
+impl Channel {
+    pub fn new(channel: &str) -> Self {
+        Channel(channel.to_string())
+    }
+}
+
+impl Matcher for Channel {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        msg.channel
+            .eq_ignore_ascii_case(&self.0)
+            .then(Matched::default)
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(format!("channel {}", self.0))
+    }
+}
+
+/// messages from the nick, case-insensitive
+#[derive(Clone, Debug)]
+pub struct Nick(String);
+
+impl Nick {
+    pub fn new(nick: &str) -> Self {
+        Nick(nick.to_string())
+    }
+}
+
+impl Matcher for Nick {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        msg.nick
+            .eq_ignore_ascii_case(&self.0)
+            .then(Matched::default)
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(format!("nick {}", self.0))
+    }
+}
+
+/// messages or events of the kind, e.g. `MessageKind::Join`
+#[derive(Clone, Debug)]
+pub struct Kind(pub MessageKind);
+
+impl Matcher for Kind {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        (msg.kind == self.0).then(Matched::default)
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(format!("kind {:?}", self.0))
+    }
+}
+
+/// arbitrary condition on the message
+pub struct Predicate<F>(pub F);
+
+impl<F> Matcher for Predicate<F>
+where
+    F: Fn(&Message) -> bool + Send + Sync,
+{
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        (self.0)(msg).then(Matched::default)
+    }
+}
+
+/// every matcher matches, captures are taken from the first that has them
+pub struct All(pub Vec<Box<dyn Matcher>>);
+
+impl Matcher for All {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        let mut matched = Matched::default();
+        for matcher in &self.0 {
+            let m = matcher.matches(msg)?;
+            if matched.captures.is_none() {
+                matched.captures = m.captures;
+            }
+        }
+        Some(matched)
+    }
+
+    fn describe(&self) -> Option<String> {
+        describe_all("all", &self.0)
+    }
+}
+
+/// the first matcher that matches
+pub struct Any(pub Vec<Box<dyn Matcher>>);
+
+impl Matcher for Any {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        self.0.iter().find_map(|matcher| matcher.matches(msg))
+    }
+
+    fn describe(&self) -> Option<String> {
+        describe_all("any", &self.0)
+    }
+}
+
+/// the matcher does not match
+pub struct Not(pub Box<dyn Matcher>);
+
+impl Matcher for Not {
+    fn matches<'a>(&self, msg: &'a Message) -> Option<Matched<'a>> {
+        match self.0.matches(msg) {
+            Some(_) => None,
+            None => Some(Matched::default()),
+        }
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(format!("not({})", self.0.describe()?))
+    }
+}
+
+fn describe_all(name: &str, matchers: &[Box<dyn Matcher>]) -> Option<String> {
+    let parts = matchers
+        .iter()
+        .map(|m| m.describe())
+        .collect::<Option<Vec<String>>>()?;
+    Some(format!("{}({})", name, parts.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matchers() {
+        assert!(Pattern::new("(").is_err());
+
+        let text = Message::new("#ops", "alice", "deploy web", MessageKind::Text);
+        let join = Message::new("#ops", "bob", "", MessageKind::Join);
+
+        let pattern = Pattern::new(r"deploy (?P<app>\w+)").unwrap();
+        let matched = pattern.matches(&text).unwrap();
+        assert_eq!(&matched.captures.unwrap()["app"], "web");
+        // events have no text to match
+        assert!(Pattern::new("").unwrap().matches(&join).is_none());
+
+        let ops_deploy = All(vec![
+            Box::new(Channel::new("#OPS")),
+            Box::new(Not(Box::new(Nick::new("mallory")))),
+            Box::new(pattern.clone()),
+        ]);
+        let matched = ops_deploy.matches(&text).unwrap();
+        assert_eq!(&matched.captures.unwrap()["app"], "web");
+        assert!(ops_deploy.matches(&join).is_none());
+        assert_eq!(
+            ops_deploy.describe().unwrap(),
+            r"all(channel #OPS, not(nick mallory), deploy (?P<app>\w+))"
+        );
+
+        let event = Any(vec![
+            Box::new(Kind(MessageKind::Join)),
+            Box::new(Predicate(|msg: &Message| msg.nick == "alice")),
+        ]);
+        assert!(event.matches(&join).is_some());
+        assert!(event.matches(&text).is_some());
+        assert!(event.describe().is_none());
+    }
+}
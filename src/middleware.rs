@@ -0,0 +1,228 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::{
+    bot::{Bot, Message},
+    context::Context,
+    http::Route,
+    server::{Capabilities, SendResult, Server},
+};
+
+/// Hooks around message dispatch, see `Bot::middleware`.
+///
+/// Every stage runs the middleware in registration order and stops at the
+/// first one that drops or blocks.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// incoming message before the handlers, may modify it, false drops it
+    async fn receive(&self, _bot: &Bot, _msg: &mut Message) -> bool {
+        true
+    }
+    /// before a matched handler runs, false skips the handler
+    async fn listener(&self, _ctx: &Context<'_>) -> bool {
+        true
+    }
+    /// outgoing text on its way to the server, None blocks it
+    async fn response(&self, _channel: &str, text: String) -> Option<String> {
+        Some(text)
+    }
+}
+
+pub(crate) type Chain = Arc<RwLock<Vec<Arc<dyn Middleware>>>>;
+
+/// snapshot, the lock is not held while middleware runs
+pub(crate) fn chain(middleware: &Chain) -> Vec<Arc<dyn Middleware>> {
+    middleware.read().unwrap().clone()
+}
+
+/// ignores messages from the given nicks
+pub struct Ignore {
+    nicks: Vec<String>,
+}
+
+impl Ignore {
+    pub fn new(nicks: &[&str]) -> Self {
+        Ignore {
+            nicks: nicks.iter().map(|n| n.to_lowercase()).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Ignore {
+    async fn receive(&self, _bot: &Bot, msg: &mut Message) -> bool {
+        !self.nicks.contains(&msg.nick.to_lowercase())
+    }
+}
+
+/// Server of the bot, passes outgoing text through the response middleware.
+///
+/// Messages blocked by middleware count as sent.
+pub(crate) struct Output {
+    pub(crate) inner: Arc<dyn Server>,
+    pub(crate) middleware: Chain,
+}
+
+impl Output {
+    async fn filter(&self, channel: &str, text: &str) -> Option<String> {
+        let mut text = text.to_string();
+        for m in chain(&self.middleware) {
+            text = m.response(channel, text).await?;
+        }
+        Some(text)
+    }
+}
+
+#[async_trait]
+impl Server for Output {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        self.inner.connect(tx).await
+    }
+
+    async fn disconnect(&self) {
+        self.inner.disconnect().await
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        match self.filter(channel, message).await {
+            Some(message) => self.inner.send(channel, &message).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn reply(&self, channel: &str, nick: &str, message: &str) -> SendResult {
+        match self.filter(channel, message).await {
+            Some(message) => self.inner.reply(channel, nick, &message).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        match self.filter(channel, action).await {
+            Some(action) => self.inner.emote(channel, &action).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn notice(&self, channel: &str, message: &str) -> SendResult {
+        match self.filter(channel, message).await {
+            Some(message) => self.inner.notice(channel, &message).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        match self.filter(channel, topic).await {
+            Some(topic) => self.inner.topic(channel, &topic).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn join(&self, channel: &str) -> SendResult {
+        self.inner.join(channel).await
+    }
+
+    async fn part(&self, channel: &str) -> SendResult {
+        self.inner.part(channel).await
+    }
+
+    async fn react(&self, channel: &str, id: &str, reaction: &str) -> SendResult {
+        self.inner.react(channel, id, reaction).await
+    }
+
+    async fn send_thread_reply(&self, channel: &str, thread: &str, message: &str) -> SendResult {
+        match self.filter(channel, message).await {
+            Some(message) => {
+                self.inner
+                    .send_thread_reply(channel, thread, &message)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn monitor(&self, nicks: &[String]) {
+        self.inner.monitor(nicks).await
+    }
+
+    async fn unmonitor(&self, nicks: &[String]) {
+        self.inner.unmonitor(nicks).await
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        self.inner.routes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        action::Action,
+        server::test::{Sent, TestServer},
+    };
+
+    use super::*;
+
+    /// only alice may ping, output is shouted and secrets are blocked
+    struct Policy;
+
+    #[async_trait]
+    impl Middleware for Policy {
+        async fn receive(&self, _bot: &Bot, msg: &mut Message) -> bool {
+            msg.message = msg.message.replace("PING", "ping");
+            true
+        }
+
+        async fn listener(&self, ctx: &Context<'_>) -> bool {
+            ctx.nick() == "alice"
+        }
+
+        async fn response(&self, _channel: &str, text: String) -> Option<String> {
+            (!text.contains("secret")).then(|| text.to_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.respond("ping", Action::ping).unwrap();
+        bot.middleware(Ignore::new(&["Mallory"]));
+        bot.middleware(Policy);
+
+        server
+            .inject(&mut bot, "#ops", "alice", "hongbot: PING")
+            .await;
+        server
+            .inject(&mut bot, "#ops", "bob", "hongbot: ping")
+            .await;
+        server
+            .inject(&mut bot, "#ops", "mallory", "hongbot: ping")
+            .await;
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![Sent::Reply {
+                channel: "#ops".to_string(),
+                nick: "alice".to_string(),
+                message: "PONG".to_string(),
+            }]
+        );
+
+        bot.send("#ops", "the secret is 42").await.unwrap();
+        bot.server.emote("#ops", "waves").await.unwrap();
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![Sent::Emote {
+                channel: "#ops".to_string(),
+                action: "WAVES".to_string(),
+            }]
+        );
+    }
+}
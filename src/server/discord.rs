@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    future,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::DiscordConfig,
+};
+
+use super::{
+    net::{request, send_error, stopped, ws_connect, Backoff, Socket},
+    SendError, SendResult, Server,
+};
+
+const API_URL: &str = "https://discord.com/api/v10";
+const GATEWAY_QUERY: &str = "/?v=10&encoding=json";
+// characters per message
+const MAX_MESSAGE: usize = 2000;
+// 2015-01-01T00:00:00Z in milliseconds, snowflake timestamps count from it
+const DISCORD_EPOCH: u64 = 1420070400000;
+
+// GUILDS | GUILD_MESSAGES | DIRECT_MESSAGES | MESSAGE_CONTENT
+const INTENTS: u64 = 1 | 1 << 9 | 1 << 12 | 1 << 15;
+
+/// Discord adapter, events over the gateway websocket and sending with REST.
+///
+/// Mentions of the bot (`<@id>`) are translated into its name so `respond`
+/// patterns match as they do on irc.
+#[derive(Debug)]
+pub struct Discord {
+    name: String,
+    config: DiscordConfig,
+    accepted: watch::Sender<bool>,
+    users: Arc<Mutex<HashMap<String, String>>>,
+}
+
+#[derive(Debug, Error)]
+enum DiscordError {
+    #[error("discord api error: {0} {1}")]
+    Api(u16, Value),
+    /// close code retrying won't fix, e.g. a bad token
+    #[error("gateway closed: {0} {1}")]
+    Fatal(u16, String),
+}
+
+// authentication failed, invalid shard, sharding required, invalid api
+// version, invalid intents, disallowed intents
+const FATAL_CLOSE: [u16; 6] = [4004, 4010, 4011, 4012, 4013, 4014];
+
+/// gateway opcodes
+mod op {
+    pub const DISPATCH: u64 = 0;
+    pub const HEARTBEAT: u64 = 1;
+    pub const IDENTIFY: u64 = 2;
+    pub const RESUME: u64 = 6;
+    pub const RECONNECT: u64 = 7;
+    pub const INVALID_SESSION: u64 = 9;
+    pub const HELLO: u64 = 10;
+    pub const HEARTBEAT_ACK: u64 = 11;
+}
+
+/// gateway session, kept across reconnects to resume
+#[derive(Debug, Default)]
+struct Session {
+    id: Option<String>,
+    resume_url: Option<String>,
+    seq: Option<u64>,
+    user_id: String,
+}
+
+#[derive(Clone, Debug)]
+struct Api {
+    url: String,
+    token: String,
+}
+
+impl Api {
+    async fn call(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+        let url = format!("{}{}", self.url, path);
+        let headers = [format!("Authorization: Bot {}", self.token)];
+        let (code, resp) = request(method, &url, &headers, body).await?;
+        if !(200..300).contains(&code) {
+            return Err(DiscordError::Api(code, resp).into());
+        }
+        Ok(resp)
+    }
+}
+
+impl Discord {
+    pub fn new(name: String, config: DiscordConfig) -> Self {
+        Discord {
+            name,
+            config,
+            accepted: watch::channel(false).0,
+            users: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn api(&self) -> Api {
+        Api {
+            url: self
+                .config
+                .api_url
+                .clone()
+                .unwrap_or_else(|| API_URL.to_string()),
+            token: self.config.token.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Discord {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let api = self.api();
+        let gateway_url = match &self.config.gateway_url {
+            Some(url) => url.clone(),
+            None => {
+                let resp = api.call("GET", "/gateway/bot", None).await?;
+                resp["url"].as_str().unwrap_or_default().to_string()
+            }
+        };
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let name = self.name.clone();
+        let users = self.users.clone();
+        let handle = tokio::spawn(async move {
+            let mut session = Session::default();
+            let mut backoff = Backoff::default();
+            while *accepted.borrow() {
+                let url = session.resume_url.as_ref().unwrap_or(&gateway_url);
+                let url = format!("{}{}", url.trim_end_matches('/'), GATEWAY_QUERY);
+                let mut socket = match ws_connect(&url).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::error!("gateway connect fail: {e}");
+                        backoff.wait(&mut accepted).await;
+                        continue;
+                    }
+                };
+                log::trace!("Connected to the server!");
+
+                let ctx = Context {
+                    token: &api.token,
+                    name: &name,
+                    users: &users,
+                    tx: &tx,
+                };
+                // events are numbered once the gateway accepted the session
+                let seq = session.seq;
+                let result = run(&mut socket, &ctx, &mut session, &mut accepted).await;
+                socket.close(None).await.ok();
+                if session.seq != seq {
+                    backoff.reset();
+                }
+                if let Err(e) = result {
+                    log::error!("gateway fail: {e}");
+                    if let Some(DiscordError::Fatal(..)) = e.downcast_ref() {
+                        break;
+                    }
+                }
+                if tx.is_closed() {
+                    log::error!("receiver dropped, stop reading");
+                    break;
+                }
+                backoff.wait(&mut accepted).await;
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let content = encode(&self.users.lock().unwrap(), message);
+        if content.chars().count() > MAX_MESSAGE {
+            return Err(SendError::TooLong(MAX_MESSAGE));
+        }
+        self.api()
+            .call(
+                "POST",
+                &format!("/channels/{}/messages", channel),
+                Some(&json!({ "content": content })),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                send_error(e, |err: &DiscordError| {
+                    let DiscordError::Api(code, resp) = err else {
+                        return None;
+                    };
+                    match (code, resp["code"].as_u64()) {
+                        (429, _) => Some(SendError::RateLimited(
+                            resp["retry_after"].as_f64().map(Duration::from_secs_f64),
+                        )),
+                        // Unknown Channel, Missing Access
+                        (404, _) | (_, Some(10003)) | (_, Some(50001)) => {
+                            Some(SendError::UnknownTarget(channel.to_string()))
+                        }
+                        _ => None,
+                    }
+                })
+            })
+    }
+}
+
+struct Context<'a> {
+    token: &'a str,
+    name: &'a str,
+    users: &'a Mutex<HashMap<String, String>>,
+    tx: &'a UnboundedSender<Message>,
+}
+
+/// one gateway connection, returns when the connection should be resumed or re-established
+async fn run(
+    socket: &mut Socket,
+    ctx: &Context<'_>,
+    session: &mut Session,
+    accepted: &mut watch::Receiver<bool>,
+) -> Result<()> {
+    let mut interval = None;
+    let mut last_heartbeat = Instant::now();
+    let mut acked = true;
+
+    loop {
+        let heartbeat_due = async {
+            match interval {
+                Some(interval) => sleep_until(last_heartbeat + interval).await,
+                // no heartbeat before HELLO
+                None => future::pending().await,
+            }
+        };
+
+        let msg = tokio::select! {
+            _ = stopped(accepted) => return Ok(()),
+            _ = heartbeat_due => {
+                if !acked {
+                    log::error!("heartbeat not acknowledged, reconnecting");
+                    return Ok(());
+                }
+                heartbeat(socket, session).await?;
+                last_heartbeat = Instant::now();
+                acked = false;
+                continue;
+            }
+            msg = socket.next() => msg,
+        };
+
+        let text = match msg {
+            Some(Ok(WsMessage::Text(text))) => text,
+            Some(Ok(WsMessage::Close(frame))) => {
+                log::trace!("gateway closed: {:?}", frame);
+                return match frame {
+                    Some(frame) if FATAL_CLOSE.contains(&u16::from(frame.code)) => {
+                        Err(DiscordError::Fatal(frame.code.into(), frame.reason.to_string()).into())
+                    }
+                    _ => Ok(()),
+                };
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(()),
+        };
+
+        let payload: Value = serde_json::from_str(&text)?;
+        match payload["op"].as_u64() {
+            Some(op::HELLO) => {
+                let ms = payload["d"]["heartbeat_interval"].as_u64().unwrap_or(41250);
+                interval = Some(Duration::from_millis(ms));
+                last_heartbeat = Instant::now();
+                let greeting = match (&session.id, session.seq) {
+                    (Some(id), Some(seq)) => json!({
+                        "op": op::RESUME,
+                        "d": { "token": ctx.token, "session_id": id, "seq": seq },
+                    }),
+                    _ => json!({
+                        "op": op::IDENTIFY,
+                        "d": {
+                            "token": ctx.token,
+                            "intents": INTENTS,
+                            "properties": {
+                                "os": std::env::consts::OS,
+                                "browser": "hongbot",
+                                "device": "hongbot",
+                            },
+                        },
+                    }),
+                };
+                socket.send(WsMessage::Text(greeting.to_string())).await?;
+            }
+            Some(op::HEARTBEAT) => {
+                heartbeat(socket, session).await?;
+                last_heartbeat = Instant::now();
+            }
+            Some(op::HEARTBEAT_ACK) => acked = true,
+            Some(op::RECONNECT) => {
+                log::trace!("reconnect requested");
+                return Ok(());
+            }
+            Some(op::INVALID_SESSION) => {
+                if payload["d"].as_bool() != Some(true) {
+                    *session = Session::default();
+                }
+                return Ok(());
+            }
+            Some(op::DISPATCH) => {
+                if let Some(seq) = payload["s"].as_u64() {
+                    session.seq = Some(seq);
+                }
+                dispatch(ctx, session, payload["t"].as_str(), &payload["d"])?;
+            }
+            _ => log::trace!("{}", text),
+        }
+    }
+}
+
+async fn heartbeat(socket: &mut Socket, session: &Session) -> Result<()> {
+    let payload = json!({ "op": op::HEARTBEAT, "d": session.seq });
+    socket.send(WsMessage::Text(payload.to_string())).await?;
+    Ok(())
+}
+
+/// fails if the bot is gone
+fn dispatch(ctx: &Context, session: &mut Session, event: Option<&str>, d: &Value) -> Result<()> {
+    match event {
+        Some("READY") => {
+            session.id = d["session_id"].as_str().map(String::from);
+            session.resume_url = d["resume_gateway_url"].as_str().map(String::from);
+            session.user_id = d["user"]["id"].as_str().unwrap_or_default().to_string();
+            log::trace!("ready as {}", session.user_id);
+        }
+        Some("MESSAGE_CREATE") => {
+            let mut users = ctx.users.lock().unwrap();
+            if let Some(msg) = to_message(&mut users, &session.user_id, ctx.name, d) {
+                ctx.tx.send(msg)?;
+            }
+        }
+        _ => log::trace!("{:?}", event),
+    }
+    Ok(())
+}
+
+fn to_message(
+    users: &mut HashMap<String, String>,
+    user_id: &str,
+    name: &str,
+    d: &Value,
+) -> Option<Message> {
+    let author = &d["author"];
+    let author_id = author["id"].as_str()?;
+    if author_id == user_id || author["bot"].as_bool() == Some(true) {
+        return None;
+    }
+
+    let nick = author["username"].as_str()?.to_string();
+    users.insert(nick.clone(), author_id.to_string());
+
+    let mut message = d["content"].as_str().unwrap_or_default().to_string();
+    let mentions = d["mentions"].as_array().cloned().unwrap_or_default();
+    for mention in mentions {
+        let (Some(id), Some(username)) = (mention["id"].as_str(), mention["username"].as_str())
+        else {
+            continue;
+        };
+        let replacement = if id == user_id {
+            name.to_string()
+        } else {
+            format!("@{}", username)
+        };
+        message = message
+            .replace(&format!("<@{}>", id), &replacement)
+            .replace(&format!("<@!{}>", id), &replacement);
+    }
+
+    let mut msg = Message::new(
+        d["channel_id"].as_str()?,
+        &nick,
+        &message,
+        MessageKind::Text,
+    );
+    let id = d["id"].as_str();
+    // snowflakes hold milliseconds since the discord epoch
+    if let Some(ms) = id.and_then(|id| id.parse::<u64>().ok()) {
+        msg.time = UNIX_EPOCH + Duration::from_millis((ms >> 22) + DISCORD_EPOCH);
+    }
+    msg.id = id.map(String::from);
+    msg.direct = d["guild_id"].is_null();
+    msg.server = Some(ServerType::Discord);
+    msg.account = Some(author_id.to_string());
+    msg.raw = Some(d.to_string());
+    Some(msg)
+}
+
+/// a leading known `nick:` becomes a mention
+fn encode(users: &HashMap<String, String>, message: &str) -> String {
+    if let Some((nick, rest)) = message.split_once(": ") {
+        if let Some(id) = users.get(nick) {
+            return format!("<@{}>: {}", id, rest);
+        }
+    }
+    message.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::server::stub;
+
+    use super::*;
+
+    fn recv_op(ws: &mut tungstenite::WebSocket<std::net::TcpStream>, op: u64) -> Value {
+        loop {
+            let payload = stub::read_json(ws);
+            if payload["op"] == op {
+                return payload;
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_message() {
+        let mut users = HashMap::new();
+        let d = json!({
+            "id": "175928847299117063",
+            "channel_id": "C1",
+            "content": "<@!100> ping <@200>",
+            "author": { "id": "200", "username": "alice" },
+            "mentions": [
+                { "id": "100", "username": "hongbot" },
+                { "id": "200", "username": "alice" },
+            ],
+        });
+        let msg = to_message(&mut users, "100", "hongbot", &d).unwrap();
+        assert_eq!(msg.channel, "C1");
+        assert_eq!(msg.nick, "alice");
+        assert_eq!(msg.message, "hongbot ping @alice");
+        assert_eq!(msg.time, UNIX_EPOCH + Duration::from_millis(1462015105796));
+        assert_eq!(msg.id.as_deref(), Some("175928847299117063"));
+        assert!(msg.direct);
+        assert_eq!(msg.account.as_deref(), Some("200"));
+        assert_eq!(encode(&users, "alice: pong"), "<@200>: pong");
+
+        // own messages are ignored
+        let d = json!({
+            "channel_id": "C1",
+            "content": "pong",
+            "author": { "id": "100", "username": "hongbot", "bot": true },
+        });
+        assert!(to_message(&mut users, "100", "hongbot", &d).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        // local stand-in for the gateway and REST api
+        let (listener, ws_addr) = stub::listen();
+        let ws_url = format!("ws://{}", ws_addr);
+        let (posted_tx, mut posted_rx) = unbounded_channel::<(String, Value)>();
+        let http_addr = stub::http(move |req| {
+            posted_tx.send((req.url, req.body)).unwrap();
+            json!({})
+        });
+
+        let (resume_tx, mut resume_rx) = unbounded_channel::<Value>();
+        let resume_url = ws_url.clone();
+        thread::spawn(move || {
+            let hello = json!({ "op": op::HELLO, "d": { "heartbeat_interval": 100 } });
+
+            let mut ws = stub::accept(&listener);
+            stub::write_json(&mut ws, &hello);
+            let identify = recv_op(&mut ws, op::IDENTIFY);
+            assert_eq!(identify["d"]["token"], "secret");
+
+            let ready = json!({
+                "op": op::DISPATCH, "s": 1, "t": "READY",
+                "d": {
+                    "session_id": "s1",
+                    "resume_gateway_url": resume_url,
+                    "user": { "id": "100", "username": "hongbot" },
+                },
+            });
+            stub::write_json(&mut ws, &ready);
+            let create = json!({
+                "op": op::DISPATCH, "s": 2, "t": "MESSAGE_CREATE",
+                "d": {
+                    "channel_id": "C1",
+                    "content": "<@100> ping",
+                    "author": { "id": "200", "username": "alice" },
+                    "mentions": [{ "id": "100", "username": "hongbot" }],
+                },
+            });
+            stub::write_json(&mut ws, &create);
+
+            // heartbeats carry the last sequence number
+            let beat = recv_op(&mut ws, op::HEARTBEAT);
+            assert_eq!(beat["d"], 2);
+            stub::write_json(&mut ws, &json!({ "op": op::HEARTBEAT_ACK }));
+            stub::write_json(&mut ws, &json!({ "op": op::RECONNECT }));
+
+            // client resumes the session on a new connection
+            let mut ws = stub::accept(&listener);
+            stub::write_json(&mut ws, &hello);
+            resume_tx.send(recv_op(&mut ws, op::RESUME)).unwrap();
+            while ws.read_message().is_ok() {}
+        });
+
+        let discord = Discord::new(
+            "hongbot".to_string(),
+            DiscordConfig {
+                token: "secret".to_string(),
+                api_url: Some(format!("http://{}", http_addr)),
+                gateway_url: Some(ws_url),
+            },
+        );
+        let (tx, mut rx) = unbounded_channel();
+        let handle = discord.connect(tx).await.unwrap();
+
+        stub::recv_message(&mut rx, "C1", "alice", "hongbot ping").await;
+        let resume = stub::recv(&mut resume_rx).await;
+        assert_eq!(resume["d"]["session_id"], "s1");
+        assert_eq!(resume["d"]["seq"], 2);
+
+        discord.send("C1", "alice: pong").await.unwrap();
+        let (url, body) = stub::recv(&mut posted_rx).await;
+        assert_eq!(url, "/channels/C1/messages");
+        assert_eq!(body["content"], "<@200>: pong");
+
+        discord.disconnect().await;
+        stub::join(handle).await;
+    }
+
+    #[tokio::test]
+    async fn test_fatal_close() {
+        let (listener, ws_addr) = stub::listen();
+        thread::spawn(move || {
+            let mut ws = stub::accept(&listener);
+            let hello = json!({ "op": op::HELLO, "d": { "heartbeat_interval": 45000 } });
+            stub::write_json(&mut ws, &hello);
+            recv_op(&mut ws, op::IDENTIFY);
+            ws.close(Some(tungstenite::protocol::CloseFrame {
+                code: 4004.into(),
+                reason: "Authentication failed.".into(),
+            }))
+            .unwrap();
+            while ws.read_message().is_ok() {}
+        });
+
+        let discord = Discord::new(
+            "hongbot".to_string(),
+            DiscordConfig {
+                token: "bad".to_string(),
+                api_url: None,
+                gateway_url: Some(format!("ws://{}", ws_addr)),
+            },
+        );
+        let (tx, _rx) = unbounded_channel();
+        let handle = discord.connect(tx).await.unwrap();
+        // gives up instead of identifying again
+        stub::join(handle).await;
+    }
+}
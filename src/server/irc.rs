@@ -1,25 +1,50 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    sync::{mpsc::Sender, Arc, RwLock},
-    thread::{self, JoinHandle},
+    collections::HashMap,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+    time::{interval, sleep},
+};
 
-use crate::{bot::Message, config::IrcConfig};
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::IrcConfig,
+};
 
-use super::Server;
+use super::{net::stopped, Capabilities, SendError, SendResult, Server};
 
 const CRLF: &str = "\r\n";
+// end a command, text relayed from other networks may contain them
+const LINE_BREAKS: [char; 2] = ['\r', '\n'];
+const ISON_INTERVAL: u64 = 60;
+// channel prefixes, other targets are nicks
+const CHANTYPES: [char; 4] = ['#', '&', '+', '!'];
+// IRCv3 capabilities filling the message metadata
+const CAPS: &str = "server-time message-tags account-tag";
+// including CRLF, RFC 1459 2.3
+const MAX_LINE: usize = 512;
+
+/// write half of the connection, shared by the bot and the reader task
+type Writer = Arc<tokio::sync::Mutex<Option<OwnedWriteHalf>>>;
 
 #[derive(Debug)]
 pub struct Irc {
     config: IrcConfig,
-    accepted: Option<Arc<RwLock<bool>>>,
-    stream: Option<TcpStream>,
+    accepted: watch::Sender<bool>,
+    writer: Writer,
+    presence: Arc<Mutex<Presence>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -29,12 +54,57 @@ enum IrcCommand {
     User,
     Privmsg,
     Join,
+    Part,
+    /// RPL_ISUPPORT (005)
+    Isupport,
+    /// RPL_ISON (303)
+    Ison,
+    /// RPL_MONONLINE (730)
+    MonOnline,
+    /// RPL_MONOFFLINE (731)
+    MonOffline,
+}
+
+/// Watched nicks and their last known presence.
+///
+/// Shared by the reader task (MONITOR replies) and the ISON poller,
+/// keys are lowercased nicks.
+#[derive(Debug, Default)]
+struct Presence {
+    monitor: bool,
+    watched: HashMap<String, Option<bool>>,
+}
+
+impl Presence {
+    fn new(nicks: &[String]) -> Self {
+        Presence {
+            monitor: false,
+            watched: nicks.iter().map(|n| (n.to_lowercase(), None)).collect(),
+        }
+    }
+
+    fn nicks(&self) -> Vec<String> {
+        self.watched.keys().cloned().collect()
+    }
+
+    /// returns true if the nick is watched and its presence has changed
+    fn update(&mut self, nick: &str, online: bool) -> bool {
+        match self.watched.get_mut(&nick.to_lowercase()) {
+            Some(state) if *state != Some(online) => {
+                *state = Some(online);
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
 struct IrcMessage {
     raw: String,
+    /// IRCv3 tags, `@time=...;msgid=... :nick!user@host PRIVMSG ...`
+    tags: HashMap<String, String>,
     servername: Option<String>,
     hostname: Option<String>,
     nick: Option<String>,
@@ -56,12 +126,25 @@ impl IrcMessage {
             return Err(IrcError::InvalidMessage.into());
         }
 
+        let mut tags = HashMap::new();
+        let line = match raw.strip_prefix('@') {
+            Some(tagged) => {
+                let (tagged, rest) = tagged.split_once(' ').ok_or(IrcError::InvalidMessage)?;
+                for tag in tagged.split(';') {
+                    let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                    tags.insert(key.to_string(), unescape_tag(value));
+                }
+                rest
+            }
+            None => raw,
+        };
+
         let mut s: Vec<&str>;
         let mut nick = None;
         let mut servername = None;
         let mut hostname = None;
-        if raw.chars().next().unwrap().eq(&':') {
-            let v = raw[1..].split(' ').collect::<Vec<&str>>();
+        if let Some(prefixed) = line.strip_prefix(':') {
+            let v = prefixed.split(' ').collect::<Vec<&str>>();
             s = v[1..].to_vec();
             let from = v[0];
             if let Some(i) = from.find('!') {
@@ -72,7 +155,7 @@ impl IrcMessage {
                 };
             };
         } else {
-            s = raw.split(' ').collect::<Vec<&str>>();
+            s = line.split(' ').collect::<Vec<&str>>();
         }
 
         let cmd = s[0].replace(';', "");
@@ -84,11 +167,17 @@ impl IrcMessage {
             "user" => Ok(IrcCommand::User),
             "privmsg" => Ok(IrcCommand::Privmsg),
             "join" => Ok(IrcCommand::Join),
+            "part" => Ok(IrcCommand::Part),
+            "005" => Ok(IrcCommand::Isupport),
+            "303" => Ok(IrcCommand::Ison),
+            "730" => Ok(IrcCommand::MonOnline),
+            "731" => Ok(IrcCommand::MonOffline),
             _ => Err(IrcError::UnknownCommand),
         }?;
 
         Ok(Self {
             raw: raw.to_string(),
+            tags,
             servername,
             hostname,
             nick,
@@ -96,20 +185,73 @@ impl IrcMessage {
             params: s.join(" "),
         })
     }
+
+    /// user@host of the sender
+    fn host(&self) -> Option<String> {
+        match (&self.servername, &self.hostname) {
+            (Some(user), Some(host)) => Some(format!("{}@{}", user, host)),
+            _ => None,
+        }
+    }
+
+    /// message with the metadata of this line
+    fn to_message(&self, channel: &str, nick: &str, message: &str, kind: MessageKind) -> Message {
+        let mut msg = Message::new(channel, nick, message, kind);
+        // server-time, e.g. 2011-10-19T16:40:51.620Z
+        if let Some(time) = self.tags.get("time") {
+            match humantime::parse_rfc3339_weak(time) {
+                Ok(time) => msg.time = time,
+                Err(e) => log::error!("unexpected server-time {:?}: {e}", time),
+            }
+        }
+        msg.id = self.tags.get("msgid").cloned();
+        msg.account = self.tags.get("account").cloned();
+        msg.server = Some(ServerType::Irc);
+        msg.host = self.host();
+        msg.raw = Some(self.raw.clone());
+        msg.tags = self.tags.clone();
+        msg
+    }
+
+    /// trailing parameter, the part after the first " :"
+    fn trailing(&self) -> &str {
+        match self.params.find(" :") {
+            Some(i) => &self.params[(i + 2)..],
+            None => "",
+        }
+    }
 }
 
 impl Irc {
     pub fn new(config: IrcConfig) -> Self {
+        let presence = Presence::new(config.monitor.as_deref().unwrap_or_default());
         Irc {
             config,
-            accepted: None,
-            stream: None,
+            accepted: watch::channel(false).0,
+            writer: Arc::default(),
+            presence: Arc::new(Mutex::new(presence)),
         }
     }
+
+    async fn write(&self, command: &str) -> SendResult {
+        write(&self.writer, command).await
+    }
+
+    /// `text` between `prefix` and `suffix`, in as many commands as it takes
+    /// to fit `MAX_LINE`
+    async fn write_split(&self, prefix: &str, text: &str, suffix: &str) -> SendResult {
+        let max = MAX_LINE.saturating_sub(prefix.len() + suffix.len() + CRLF.len());
+        for chunk in chunks(text, max) {
+            self.write(&format!("{}{}{}", prefix, chunk, suffix))
+                .await?;
+        }
+        Ok(())
+    }
 }
 
+#[async_trait]
 impl Server for Irc {
-    fn connect(&mut self, tx: Sender<Message>) -> Result<JoinHandle<()>> {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
         // 1. pass (optional)
         // 2. nick
         // 3. user
@@ -117,17 +259,10 @@ impl Server for Irc {
         let nick = self.config.nick.clone();
         let addr = self.config.addr.clone();
 
-        let lock0 = Arc::new(RwLock::new(false));
-        let lock1 = Arc::clone(&lock0);
-        self.accepted = Some(lock0);
-
-        let mut stream0 = TcpStream::connect(addr)?;
-        self.stream = Some(stream0.try_clone().unwrap());
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        *self.writer.lock().await = Some(writer);
         log::trace!("Connected to the server!");
-        {
-            let mut accepted = lock1.write().unwrap();
-            *accepted = true;
-        }
+        self.accepted.send_replace(true);
 
         let pass = self.config.pass.clone();
         let channels = self.config.channels.clone();
@@ -141,49 +276,92 @@ impl Server for Irc {
         } else {
             nick.clone()
         };
-        let mut stream1 = stream0.try_clone().expect("stream clone fail");
-        let handle = thread::spawn(move || {
-            let mut buf = [0; 4096];
+        let writer = self.writer.clone();
+        let presence = self.presence.clone();
+        let own = nick.clone();
+        let mut accepted = self.accepted.subscribe();
+        let handle = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
             loop {
-                match stream1.read(&mut buf) {
-                    Ok(size) => {
-                        let message =
-                            std::str::from_utf8(&buf[0..size]).expect("unexpected string bytes");
-                        let message = String::from(message);
-                        // cleanup buffer
-                        buf[0..size].iter_mut().for_each(|x| *x = 0);
-
+                let line = tokio::select! {
+                    _ = stopped(&mut accepted) => break,
+                    line = read_line(&mut reader) => line,
+                };
+                match line {
+                    Ok(Some(message)) => {
                         // ignore unknown commands
                         let irc_msg = IrcMessage::from(&message).ok();
                         if let Some(msg) = irc_msg {
-                            match msg.command {
+                            let result = match msg.command {
                                 IrcCommand::Ping => {
-                                    handle_ping(&mut stream1, msg);
+                                    handle_ping(&writer, msg).await;
+                                    Ok(())
                                 }
-                                IrcCommand::Privmsg => {
-                                    handle_privmsg(&tx, msg);
+                                IrcCommand::Privmsg => handle_privmsg(&tx, msg),
+                                IrcCommand::Join | IrcCommand::Part => {
+                                    handle_membership(&tx, &own, msg)
+                                }
+                                IrcCommand::Isupport => {
+                                    handle_isupport(&writer, &presence, msg).await;
+                                    Ok(())
+                                }
+                                IrcCommand::Ison => handle_ison(&tx, &presence, msg),
+                                IrcCommand::MonOnline | IrcCommand::MonOffline => {
+                                    handle_monitor(&tx, &presence, msg)
                                 }
                                 _ => {
                                     log::trace!("{:?}", msg);
+                                    Ok(())
                                 }
+                            };
+                            if let Err(e) = result {
+                                log::error!("stop reading: {e}");
+                                break;
                             }
                         }
                     }
+                    Ok(None) => {
+                        log::error!("connection closed");
+                        break;
+                    }
                     Err(e) => {
                         log::error!("read fail: {e}");
-                        stream1.shutdown(std::net::Shutdown::Both).unwrap();
                         break;
                     }
                 }
+            }
 
-                let accepted = lock1.read().unwrap();
-                if !*accepted {
-                    match stream1.shutdown(std::net::Shutdown::Both) {
-                        Ok(()) => (),
-                        Err(e) => {
-                            log::error!("shutdown fail: {e}");
-                        }
+            if let Some(mut writer) = writer.lock().await.take() {
+                if let Err(e) = writer.shutdown().await {
+                    log::error!("shutdown fail: {e}");
+                }
+            }
+        });
+
+        // ISON fallback for servers without MONITOR, detached
+        let writer = self.writer.clone();
+        let presence = self.presence.clone();
+        let mut accepted = self.accepted.subscribe();
+        let period = Duration::from_secs(self.config.ison_interval.unwrap_or(ISON_INTERVAL));
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            // the first tick completes immediately
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = stopped(&mut accepted) => break,
+                    _ = ticker.tick() => (),
+                }
+
+                let command = {
+                    let presence = presence.lock().unwrap();
+                    if presence.monitor || presence.watched.is_empty() {
+                        continue;
                     }
+                    format!("ISON {}", presence.nicks().join(" "))
+                };
+                if let Err(e) = write(&writer, &command).await {
+                    log::error!("write ISON fail: {e}");
                     break;
                 }
             }
@@ -191,16 +369,14 @@ impl Server for Irc {
 
         let sec = Duration::from_millis(1000);
         if let Some(pass) = pass {
-            stream0
-                .write_all(format!("PASS {}{CRLF}", pass).as_bytes())
-                .expect("send PASS cmd fail");
-            thread::sleep(sec * 3);
+            self.write(&format!("PASS {}", pass)).await?;
+            sleep(sec * 3).await;
         }
 
-        stream0
-            .write_all(format!("NICK {}{CRLF}", nick).as_bytes())
-            .expect("send NICK cmd fail");
-        thread::sleep(sec * 3);
+        // registration waits for CAP END, servers without CAP ignore both
+        self.write(&format!("CAP REQ :{}", CAPS)).await?;
+        self.write(&format!("NICK {}", nick)).await?;
+        sleep(sec * 3).await;
 
         // Parameters: <username> <hostname> <servername> <realname>
         //
@@ -209,71 +385,335 @@ impl Server for Irc {
         //
         // :testnick USER guest tolmoon tolsun :Ronnie Reagan
         // ; message between servers with the nickname for which the USER command belongs to
-        stream0
-            .write_all(format!("USER {} * * :{}{CRLF}", user, realname).as_bytes())
-            .expect("send USER cmd fail");
-        thread::sleep(sec * 3);
+        self.write(&format!("USER {} * * :{}", user, realname))
+            .await?;
+        self.write("CAP END").await?;
+        sleep(sec * 3).await;
 
         for ch in &channels {
-            stream0
-                .write_all(format!("JOIN {}{CRLF}", ch).as_bytes())
-                .expect("send JOIN cmd fail");
-            thread::sleep(sec);
+            self.write(&format!("JOIN {}", ch)).await?;
+            sleep(sec).await;
         }
 
         Ok(handle)
     }
 
-    fn disconnect(&mut self) {
+    async fn disconnect(&self) {
         log::trace!("disconnect");
-        if let Some(lock) = &self.accepted {
-            let mut lock = lock.write().expect("acquire write lock fail");
-            *lock = false;
+        // the connection may already be gone
+        self.write("QUIT :Bye").await.ok();
+        self.accepted.send_replace(false);
+    }
+
+    /// one PRIVMSG per line, long lines are split
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let prefix = format!("PRIVMSG {} :", channel);
+        for line in lines(message) {
+            self.write_split(&prefix, line, "").await?;
         }
+        Ok(())
+    }
 
-        if let Some(stream) = &mut self.stream {
-            let command = format!("QUIT :Bye{CRLF}");
-            stream.write_all(command.as_bytes()).unwrap();
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        let action = lines(action).collect::<Vec<&str>>().join(" ");
+        let prefix = format!("PRIVMSG {} :\x01ACTION ", channel);
+        self.write_split(&prefix, &action, "\x01").await
+    }
+
+    async fn notice(&self, channel: &str, message: &str) -> SendResult {
+        let prefix = format!("NOTICE {} :", channel);
+        for line in lines(message) {
+            self.write_split(&prefix, line, "").await?;
         }
+        Ok(())
     }
 
-    fn send(&mut self, channel: &str, message: &str) {
-        if let Some(stream) = &mut self.stream {
-            let command = format!("PRIVMSG {} {}{}", channel, message, CRLF);
-            stream
-                .write_all(command.as_bytes())
-                .expect("write PRIVMSG fail");
+    async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        let topic = lines(topic).collect::<Vec<&str>>().join(" ");
+        self.write(&format!("TOPIC {} :{}", channel, topic)).await
+    }
+
+    async fn join(&self, channel: &str) -> SendResult {
+        self.write(&format!("JOIN {}", channel)).await
+    }
+
+    async fn part(&self, channel: &str) -> SendResult {
+        self.write(&format!("PART {}", channel)).await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            emote: true,
+            notice: true,
+            topic: true,
+            join: true,
+            part: true,
+            ..Capabilities::default()
         }
     }
+
+    async fn monitor(&self, nicks: &[String]) {
+        let monitor = {
+            let mut presence = self.presence.lock().unwrap();
+            for nick in nicks {
+                presence.watched.entry(nick.to_lowercase()).or_insert(None);
+            }
+            presence.monitor
+        };
+
+        if !monitor || nicks.is_empty() {
+            return;
+        }
+        let command = format!("MONITOR + {}", nicks.join(","));
+        if let Err(e) = self.write(&command).await {
+            log::error!("write MONITOR fail: {e}");
+        }
+    }
+
+    async fn unmonitor(&self, nicks: &[String]) {
+        let monitor = {
+            let mut presence = self.presence.lock().unwrap();
+            for nick in nicks {
+                presence.watched.remove(&nick.to_lowercase());
+            }
+            presence.monitor
+        };
+
+        if !monitor || nicks.is_empty() {
+            return;
+        }
+        let command = format!("MONITOR - {}", nicks.join(","));
+        if let Err(e) = self.write(&command).await {
+            log::error!("write MONITOR fail: {e}");
+        }
+    }
+}
+
+/// next line without its line break, `None` once the server closed the
+/// connection, text that is not UTF-8 (e.g. latin-1) is decoded lossily
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    if reader.read_until(b'\n', &mut buf).await? == 0 {
+        return Ok(None);
+    }
+    let line = String::from_utf8_lossy(&buf);
+    Ok(Some(line.trim_end_matches(LINE_BREAKS).to_string()))
 }
 
-fn handle_ping(stream: &mut TcpStream, msg: IrcMessage) {
-    let command = format!("PONG {}{CRLF}", msg.params);
-    stream.write_all(command.as_bytes()).unwrap();
+/// non-empty lines of the text
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split(LINE_BREAKS).filter(|line| !line.is_empty())
 }
 
-fn handle_privmsg(tx: &Sender<Message>, msg: IrcMessage) {
-    let nick = msg.nick.unwrap_or_else(|| "unknown".to_string());
+/// pieces of at most `max` bytes, cut on char boundaries
+fn chunks(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // not even one char fits, `write` refuses the rest
+        if end == 0 {
+            break;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    chunks.push(rest);
+    chunks
+}
+
+async fn write(writer: &Writer, command: &str) -> SendResult {
+    // a line break would start another command
+    if command.contains(LINE_BREAKS) {
+        return Err(anyhow!("line break in command: {:?}", command).into());
+    }
+    let line = format!("{}{}", command, CRLF);
+    if line.len() > MAX_LINE {
+        return Err(SendError::TooLong(MAX_LINE));
+    }
+    let mut writer = writer.lock().await;
+    let stream = writer.as_mut().ok_or(SendError::Disconnected)?;
+    stream.write_all(line.as_bytes()).await.map_err(|e| {
+        log::error!("write fail: {e}");
+        SendError::Disconnected
+    })
+}
+
+async fn handle_ping(writer: &Writer, msg: IrcMessage) {
+    if let Err(e) = write(writer, &format!("PONG {}", msg.params)).await {
+        log::error!("write PONG fail: {e}");
+    }
+}
+
+/// handlers sending to the bot fail once it dropped the receiver
+fn handle_privmsg(tx: &UnboundedSender<Message>, msg: IrcMessage) -> Result<()> {
+    let nick = msg.nick.clone().unwrap_or_else(|| "unknown".to_string());
     let params = msg.params.split(' ').collect::<Vec<&str>>();
     if params.len() < 2 {
         log::error!("unexpected privmsg format: {:?}", msg.params);
-        return;
+        return Ok(());
     }
-    let channel = String::from(params[0]);
     let mut message = params[1..].join(" ");
     if message.starts_with(':') {
         message = message[1..].to_string();
     }
-    tx.send(Message {
-        channel,
-        nick,
-        message,
-    })
-    .expect("tx send fail");
+    // CTCP ACTION, sent by `/me`
+    let (message, kind) = match message
+        .strip_prefix("\x01ACTION ")
+        .map(|m| m.trim_end_matches('\x01'))
+    {
+        Some(action) => (action.to_string(), MessageKind::Emote),
+        None => (message, MessageKind::Text),
+    };
+    // private messages are targeted at our nick, answer to the sender
+    let direct = !params[0].starts_with(CHANTYPES);
+    let channel = if direct { nick.as_str() } else { params[0] };
+    let mut message = msg.to_message(channel, &nick, &message, kind);
+    message.direct = direct;
+    tx.send(message)?;
+    Ok(())
+}
+
+fn handle_membership(tx: &UnboundedSender<Message>, own: &str, msg: IrcMessage) -> Result<()> {
+    // :alice!a@host JOIN #foo
+    // :alice!a@host PART #foo :reason
+    let Some(nick) = msg.nick.clone() else {
+        return Ok(());
+    };
+    if nick.eq_ignore_ascii_case(own) {
+        return Ok(());
+    }
+    let (channel, reason) = msg.params.split_once(' ').unwrap_or((&msg.params, ""));
+    let kind = if msg.command == IrcCommand::Join {
+        MessageKind::Join
+    } else {
+        MessageKind::Part
+    };
+    let message = msg.to_message(
+        channel.trim_start_matches(':'),
+        &nick,
+        reason.trim_start_matches(':'),
+        kind,
+    );
+    tx.send(message)?;
+    Ok(())
+}
+
+async fn handle_isupport(writer: &Writer, presence: &Mutex<Presence>, msg: IrcMessage) {
+    // :server 005 hongbot MONITOR=100 CHANTYPES=# :are supported by this server
+    let supported = msg
+        .params
+        .split(' ')
+        .take_while(|token| !token.starts_with(':'))
+        .any(|token| token == "MONITOR" || token.starts_with("MONITOR="));
+    if !supported {
+        return;
+    }
+
+    let command = {
+        let mut presence = presence.lock().unwrap();
+        if presence.monitor {
+            return;
+        }
+        presence.monitor = true;
+        log::trace!("MONITOR is supported, stop ISON polling");
+        if presence.watched.is_empty() {
+            return;
+        }
+        format!("MONITOR + {}", presence.nicks().join(","))
+    };
+    if let Err(e) = write(writer, &command).await {
+        log::error!("write MONITOR fail: {e}");
+    }
+}
+
+fn handle_ison(
+    tx: &UnboundedSender<Message>,
+    presence: &Mutex<Presence>,
+    msg: IrcMessage,
+) -> Result<()> {
+    // :server 303 hongbot :alice bob
+    let online = msg
+        .trailing()
+        .split(' ')
+        .filter(|nick| !nick.is_empty())
+        .map(|nick| nick.to_lowercase())
+        .collect::<Vec<String>>();
+
+    let mut presence = presence.lock().unwrap();
+    for nick in presence.nicks() {
+        let is_online = online.contains(&nick);
+        if presence.update(&nick, is_online) {
+            send_presence(tx, &nick, is_online)?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_monitor(
+    tx: &UnboundedSender<Message>,
+    presence: &Mutex<Presence>,
+    msg: IrcMessage,
+) -> Result<()> {
+    // :server 730 hongbot :alice!user@host,bob!user@host
+    // :server 731 hongbot :alice,bob
+    let online = msg.command == IrcCommand::MonOnline;
+    let mut presence = presence.lock().unwrap();
+    for target in msg.trailing().split(',') {
+        let nick = target.split('!').next().unwrap_or_default();
+        if !nick.is_empty() && presence.update(nick, online) {
+            send_presence(tx, nick, online)?;
+        }
+    }
+    Ok(())
+}
+
+fn send_presence(tx: &UnboundedSender<Message>, nick: &str, online: bool) -> Result<()> {
+    let kind = if online {
+        MessageKind::Online
+    } else {
+        MessageKind::Offline
+    };
+    let mut msg = Message::new("", nick, "", kind);
+    msg.server = Some(ServerType::Irc);
+    tx.send(msg)?;
+    Ok(())
+}
+
+/// value of an IRCv3 tag, `\:` is `;` and `\s` a space
+fn unescape_tag(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => (),
+        }
+    }
+    out
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::{
+        bridge::{Bridge, MAIN},
+        config::{BridgeConfig, Endpoint},
+        server::stub,
+    };
+
     use super::*;
 
     #[test]
@@ -313,4 +753,362 @@ mod tests {
         assert_eq!(msg.params, "#foo :good");
         assert_eq!(msg.nick, Some("aanoaa".to_string()));
     }
+
+    #[test]
+    fn test_irc_message_parse_numeric() {
+        let msg = IrcMessage::from(":irc.local 303 hongbot :alice bob").unwrap();
+        assert_eq!(msg.command, IrcCommand::Ison);
+        assert_eq!(msg.nick, None);
+        assert_eq!(msg.trailing(), "alice bob");
+
+        let msg = IrcMessage::from(":irc.local 730 hongbot :alice!a@host,bob!b@host").unwrap();
+        assert_eq!(msg.command, IrcCommand::MonOnline);
+        assert_eq!(msg.trailing(), "alice!a@host,bob!b@host");
+
+        let msg = IrcMessage::from(":irc.local 731 hongbot :alice").unwrap();
+        assert_eq!(msg.command, IrcCommand::MonOffline);
+        assert_eq!(msg.trailing(), "alice");
+    }
+
+    #[test]
+    fn test_handle_privmsg_action() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let msg = IrcMessage::from(":alice!a@host PRIVMSG #foo :\x01ACTION waves\x01").unwrap();
+        handle_privmsg(&tx, msg).unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.kind, MessageKind::Emote);
+        assert_eq!(msg.message, "waves");
+    }
+
+    #[test]
+    fn test_handle_privmsg_metadata() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let line = "@time=2023-01-05T08:02:59.000Z;msgid=abc;account=aanoaa;+x=a\\sb\\:c \
+                    :alice!a@host PRIVMSG hongbot :ping";
+        handle_privmsg(&tx, IrcMessage::from(line).unwrap()).unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.channel, "alice");
+        assert_eq!(msg.message, "ping");
+        assert!(msg.direct);
+        assert_eq!(msg.time, UNIX_EPOCH + Duration::from_secs(1672905779),);
+        assert_eq!(msg.id.as_deref(), Some("abc"));
+        assert_eq!(msg.account.as_deref(), Some("aanoaa"));
+        assert_eq!(msg.host.as_deref(), Some("a@host"));
+        assert_eq!(msg.server, Some(ServerType::Irc));
+        assert_eq!(msg.raw.as_deref(), Some(line));
+        assert_eq!(msg.tags["+x"], "a b;c");
+
+        handle_privmsg(
+            &tx,
+            IrcMessage::from(":alice!a@host PRIVMSG #foo :hi").unwrap(),
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.channel, "#foo");
+        assert!(!msg.direct);
+        assert!(msg.tags.is_empty());
+    }
+
+    #[test]
+    fn test_handle_membership() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        handle_membership(
+            &tx,
+            "hongbot",
+            IrcMessage::from(":alice!a@host JOIN :#foo").unwrap(),
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.kind, MessageKind::Join);
+        assert_eq!(msg.channel, "#foo");
+        assert_eq!(msg.nick, "alice");
+
+        handle_membership(
+            &tx,
+            "hongbot",
+            IrcMessage::from(":alice!a@host PART #foo :gone fishing").unwrap(),
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.kind, MessageKind::Part);
+        assert_eq!(msg.message, "gone fishing");
+
+        // own joins are not reported
+        handle_membership(
+            &tx,
+            "hongbot",
+            IrcMessage::from(":hongbot!h@host JOIN #foo").unwrap(),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // the bot is gone, the reader stops
+        drop(rx);
+        let join = IrcMessage::from(":alice!a@host JOIN #foo").unwrap();
+        assert!(handle_membership(&tx, "hongbot", join).is_err());
+    }
+
+    /// irc with its writer connected to the returned peer
+    async fn connected() -> (Irc, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let irc = Irc::new(IrcConfig {
+            nick: "hongbot".to_string(),
+            user: None,
+            pass: None,
+            realname: None,
+            addr: listener.local_addr().unwrap().to_string(),
+            channels: vec![],
+            monitor: None,
+            ison_interval: None,
+        });
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        *irc.writer.lock().await = Some(stream.into_split().1);
+        let (peer, _) = listener.accept().await.unwrap();
+        (irc, peer)
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (irc, peer) = connected().await;
+        irc.emote("#foo", "waves").await.unwrap();
+        irc.notice("#foo", "deploy started").await.unwrap();
+        irc.topic("#foo", "release day").await.unwrap();
+        irc.join("#bar").await.unwrap();
+        irc.part("#bar").await.unwrap();
+        assert!(matches!(
+            irc.topic("#foo", &"a".repeat(MAX_LINE)).await,
+            Err(SendError::TooLong(MAX_LINE))
+        ));
+        assert!(matches!(
+            irc.react("#foo", "1", "+1").await,
+            Err(SendError::Unsupported)
+        ));
+        assert!(irc.capabilities().emote);
+        assert!(!irc.capabilities().react);
+        assert!(!irc.capabilities().threads);
+
+        let mut reader = BufReader::new(peer).lines();
+        let mut lines = Vec::new();
+        for _ in 0..5 {
+            lines.push(reader.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "PRIVMSG #foo :\x01ACTION waves\x01",
+                "NOTICE #foo :deploy started",
+                "TOPIC #foo :release day",
+                "JOIN #bar",
+                "PART #bar",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let irc = Arc::new(Irc::new(IrcConfig {
+            nick: "hongbot".to_string(),
+            user: None,
+            pass: None,
+            realname: None,
+            addr: listener.local_addr().unwrap().to_string(),
+            channels: vec![],
+            monitor: None,
+            ison_interval: None,
+        }));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        // registration takes a while, the reader runs meanwhile
+        let registration = tokio::spawn({
+            let irc = irc.clone();
+            async move { irc.connect(tx).await }
+        });
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        peer.write_all(b":alice!a@host PRIVMSG #ops :caf\xe9\r\n\xff\r\n")
+            .await
+            .unwrap();
+        peer.write_all(b":alice!a@host PRIVMSG #ops :still here\r\n")
+            .await
+            .unwrap();
+        let msg = stub::recv(&mut rx).await;
+        assert_eq!(msg.message, "caf\u{fffd}");
+        let msg = stub::recv(&mut rx).await;
+        assert_eq!(msg.message, "still here");
+
+        registration.abort();
+        irc.disconnect().await;
+    }
+
+    #[test]
+    fn test_presence() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let presence = Mutex::new(Presence::new(&["Alice".to_string(), "bob".to_string()]));
+
+        // unwatched nicks are ignored
+        handle_ison(
+            &tx,
+            &presence,
+            IrcMessage::from(":irc.local 303 hongbot :alice carol").unwrap(),
+        )
+        .unwrap();
+        let mut events = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|m| (m.nick, m.kind))
+            .collect::<Vec<(String, MessageKind)>>();
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            events,
+            vec![
+                ("alice".to_string(), MessageKind::Online),
+                ("bob".to_string(), MessageKind::Offline),
+            ]
+        );
+
+        // no event without a change
+        handle_ison(
+            &tx,
+            &presence,
+            IrcMessage::from(":irc.local 303 hongbot :alice").unwrap(),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        handle_monitor(
+            &tx,
+            &presence,
+            IrcMessage::from(":irc.local 730 hongbot :Bob!b@host").unwrap(),
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.nick, "Bob");
+        assert_eq!(msg.kind, MessageKind::Online);
+    }
+
+    /// bridge of #ops on `main` and `other`, both irc
+    fn bridge(main: &Arc<dyn Server>, other: &Arc<dyn Server>, channel: &str) -> Bridge {
+        let servers = HashMap::from([
+            (MAIN.to_string(), main.clone()),
+            ("other".to_string(), other.clone()),
+        ]);
+        let bridges = [BridgeConfig {
+            left: Endpoint {
+                connection: None,
+                channel: "#ops".to_string(),
+            },
+            right: Endpoint {
+                connection: Some("other".to_string()),
+                channel: channel.to_string(),
+            },
+            joins: None,
+        }];
+        Bridge::with_servers("hongbot".to_string(), servers, &bridges)
+    }
+
+    #[tokio::test]
+    async fn test_relay_two_networks() {
+        let (efnet, efnet_peer) = connected().await;
+        let (libera, libera_peer) = connected().await;
+        let efnet: Arc<dyn Server> = Arc::new(efnet);
+        let libera: Arc<dyn Server> = Arc::new(libera);
+        let bridge = bridge(&efnet, &libera, "#ops");
+
+        // the same channel name on each network, routed by connection
+        let line = ":alice!a@host PRIVMSG #ops :hi";
+        let mut msg =
+            IrcMessage::from(line)
+                .unwrap()
+                .to_message("#ops", "alice", "hi", MessageKind::Text);
+        bridge.relay(&msg).await;
+        msg.nick = "bob".to_string();
+        msg.message = "hello".to_string();
+        msg.connection = Some("other".to_string());
+        bridge.relay(&msg).await;
+
+        let mut efnet_lines = BufReader::new(efnet_peer).lines();
+        let mut libera_lines = BufReader::new(libera_peer).lines();
+        assert_eq!(
+            libera_lines.next_line().await.unwrap().unwrap(),
+            "PRIVMSG #ops :<alice> hi"
+        );
+        assert_eq!(
+            efnet_lines.next_line().await.unwrap().unwrap(),
+            "PRIVMSG #ops :<bob> hello"
+        );
+        efnet.disconnect().await;
+        libera.disconnect().await;
+        assert_eq!(efnet_lines.next_line().await.unwrap().unwrap(), "QUIT :Bye");
+        assert_eq!(
+            libera_lines.next_line().await.unwrap().unwrap(),
+            "QUIT :Bye"
+        );
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(chunks("abcd", 4), vec!["abcd"]);
+        assert_eq!(chunks("", 4), vec![""]);
+        // 3 bytes each
+        assert_eq!(chunks("가나다", 7), vec!["가나", "다"]);
+        assert_eq!(chunks("가나", 2), vec!["가나"]);
+    }
+
+    #[tokio::test]
+    async fn test_relay_long() {
+        let (irc, peer) = connected().await;
+        let irc: Arc<dyn Server> = Arc::new(irc);
+        let slack: Arc<dyn Server> = Arc::new(crate::server::test::TestServer::new());
+        let bridge = bridge(&irc, &slack, "C01");
+
+        // 1000 bytes, a multi-byte char straddles every naive cut
+        let text = format!("a{}", "가".repeat(333));
+        assert_eq!(text.len(), 1000);
+        let mut msg = Message::new("C01", "alice", &text, MessageKind::Text);
+        msg.connection = Some("other".to_string());
+        bridge.relay(&msg).await;
+        irc.disconnect().await;
+
+        let mut reader = BufReader::new(peer).lines();
+        let mut relayed = String::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            if line == "QUIT :Bye" {
+                break;
+            }
+            assert!(line.len() + CRLF.len() <= MAX_LINE);
+            relayed.push_str(line.strip_prefix("PRIVMSG #ops :").unwrap());
+        }
+        assert_eq!(relayed, format!("<alice> {}", text));
+    }
+
+    #[tokio::test]
+    async fn test_relay_multiline() {
+        let (irc, peer) = connected().await;
+        let irc: Arc<dyn Server> = Arc::new(irc);
+        let slack: Arc<dyn Server> = Arc::new(crate::server::test::TestServer::new());
+        let bridge = bridge(&irc, &slack, "C01");
+
+        let text = "hi\r\nQUIT :x\nPRIVMSG NickServ :drop";
+        let mut msg = Message::new("C01", "mallory", text, MessageKind::Text);
+        msg.connection = Some("other".to_string());
+        bridge.relay(&msg).await;
+        irc.emote("#ops", "waves\r\nQUIT").await.unwrap();
+        assert!(irc.join("#ops\r\nQUIT").await.is_err());
+
+        let mut reader = BufReader::new(peer).lines();
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            lines.push(reader.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "PRIVMSG #ops :<mallory> hi",
+                "PRIVMSG #ops :QUIT :x",
+                "PRIVMSG #ops :PRIVMSG NickServ :drop",
+                "PRIVMSG #ops :\x01ACTION waves QUIT\x01",
+            ]
+        );
+    }
 }
@@ -0,0 +1,473 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::MatrixConfig,
+};
+
+use super::{
+    net::{request, send_error, stopped, Backoff},
+    SendError, SendResult, Server,
+};
+
+const CLIENT_API: &str = "/_matrix/client/v3";
+// long-polling timeout of /sync in milliseconds
+const SYNC_TIMEOUT: u64 = 30000;
+// bytes of an event, including the envelope
+const MAX_EVENT_SIZE: usize = 65536;
+
+/// Matrix adapter using the client-server API.
+///
+/// Room ids are used as channels and the localpart of the sender as nick,
+/// invites are accepted automatically.
+#[derive(Debug)]
+pub struct Matrix {
+    config: MatrixConfig,
+    accepted: watch::Sender<bool>,
+    txn: AtomicU64,
+}
+
+#[derive(Debug, Error)]
+enum MatrixError {
+    #[error("matrix api error: {0} {1}")]
+    Api(u16, Value),
+}
+
+#[derive(Clone, Debug)]
+struct Api {
+    url: String,
+    token: String,
+}
+
+impl Api {
+    async fn call(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+        let url = format!("{}{}{}", self.url, CLIENT_API, path);
+        let headers = [format!("Authorization: Bearer {}", self.token)];
+        let (code, resp) = request(method, &url, &headers, body).await?;
+        if !(200..300).contains(&code) {
+            return Err(MatrixError::Api(code, resp).into());
+        }
+        Ok(resp)
+    }
+
+    async fn join(&self, room: &str) -> Result<Value> {
+        log::trace!("join {}", room);
+        self.call("POST", &format!("/join/{}", encode(room)), Some(&json!({})))
+            .await
+    }
+}
+
+impl Matrix {
+    pub fn new(config: MatrixConfig) -> Self {
+        Matrix {
+            config,
+            accepted: watch::channel(false).0,
+            txn: AtomicU64::new(0),
+        }
+    }
+
+    fn api(&self) -> Api {
+        Api {
+            url: self.config.homeserver.trim_end_matches('/').to_string(),
+            token: self.config.access_token.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Matrix {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let api = self.api();
+        let whoami = api.call("GET", "/account/whoami", None).await?;
+        let user_id = whoami["user_id"].as_str().unwrap_or_default().to_string();
+        log::trace!("logged in as {}", user_id);
+
+        for room in self.config.rooms.iter().flatten() {
+            api.join(room).await?;
+        }
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            let mut direct = DirectRooms::default();
+            let mut since: Option<String> = None;
+            while *accepted.borrow() {
+                let path = match &since {
+                    Some(since) => {
+                        format!("/sync?timeout={}&since={}", SYNC_TIMEOUT, encode(since))
+                    }
+                    // skip the backlog on the initial sync
+                    None => "/sync?timeout=0".to_string(),
+                };
+                let resp = tokio::select! {
+                    _ = stopped(&mut accepted) => break,
+                    resp = api.call("GET", &path, None) => resp,
+                };
+                let resp = match resp {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        log::error!("sync fail: {e}");
+                        backoff.wait(&mut accepted).await;
+                        continue;
+                    }
+                };
+                backoff.reset();
+
+                direct.update(&user_id, &resp);
+                if let Some(invites) = resp["rooms"]["invite"].as_object() {
+                    for room in invites.keys() {
+                        if let Err(e) = api.join(room).await {
+                            log::error!("join {} fail: {e}", room);
+                        }
+                    }
+                }
+
+                if since.is_some() {
+                    for msg in to_messages(&user_id, &direct, &resp) {
+                        if tx.send(msg).is_err() {
+                            log::error!("receiver dropped, stop reading");
+                            return;
+                        }
+                    }
+                }
+                since = resp["next_batch"].as_str().map(String::from);
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let txn = format!("{}.{}", now, self.txn.fetch_add(1, Ordering::Relaxed));
+        let path = format!(
+            "/rooms/{}/send/m.room.message/{}",
+            encode(channel),
+            encode(&txn)
+        );
+        self.api()
+            .call(
+                "PUT",
+                &path,
+                Some(&json!({ "msgtype": "m.text", "body": message })),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                send_error(e, |MatrixError::Api(code, resp): &MatrixError| {
+                    match (code, resp["errcode"].as_str()) {
+                        (429, _) | (_, Some("M_LIMIT_EXCEEDED")) => Some(SendError::RateLimited(
+                            resp["retry_after_ms"].as_u64().map(Duration::from_millis),
+                        )),
+                        (413, _) | (_, Some("M_TOO_LARGE")) => {
+                            Some(SendError::TooLong(MAX_EVENT_SIZE))
+                        }
+                        (403, _) | (404, _) => Some(SendError::UnknownTarget(channel.to_string())),
+                        _ => None,
+                    }
+                })
+            })
+    }
+}
+
+/// Direct chats of the bot, kept across syncs as their events only come
+/// with the sync in which they changed.
+#[derive(Debug, Default)]
+struct DirectRooms {
+    /// listed in the `m.direct` account data
+    marked: HashSet<String>,
+    /// invites flagged `is_direct`, the inviter's client may not mark them
+    invited: HashSet<String>,
+}
+
+impl DirectRooms {
+    fn update(&mut self, user_id: &str, resp: &Value) {
+        // the event holds the whole map
+        let events = resp["account_data"]["events"].as_array();
+        if let Some(event) = events
+            .into_iter()
+            .flatten()
+            .rfind(|e| e["type"] == "m.direct")
+        {
+            let rooms = event["content"]
+                .as_object()
+                .into_iter()
+                .flat_map(|m| m.values());
+            self.marked = rooms
+                .filter_map(Value::as_array)
+                .flatten()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect();
+        }
+
+        for (room, invite) in resp["rooms"]["invite"].as_object().into_iter().flatten() {
+            let events = invite["invite_state"]["events"].as_array();
+            let direct = events.into_iter().flatten().any(|e| {
+                e["type"] == "m.room.member"
+                    && e["state_key"] == user_id
+                    && e["content"]["is_direct"] == true
+            });
+            if direct {
+                self.invited.insert(room.clone());
+            }
+        }
+    }
+
+    fn contains(&self, room: &str) -> bool {
+        self.marked.contains(room) || self.invited.contains(room)
+    }
+}
+
+/// text messages of joined rooms in a sync response
+fn to_messages(user_id: &str, direct: &DirectRooms, resp: &Value) -> Vec<Message> {
+    let mut messages = Vec::new();
+    let Some(rooms) = resp["rooms"]["join"].as_object() else {
+        return messages;
+    };
+
+    for (room, joined) in rooms {
+        let events = joined["timeline"]["events"].as_array();
+        for event in events.into_iter().flatten() {
+            if event["type"] != "m.room.message" || event["content"]["msgtype"] != "m.text" {
+                continue;
+            }
+            let (Some(sender), Some(body)) =
+                (event["sender"].as_str(), event["content"]["body"].as_str())
+            else {
+                continue;
+            };
+            if sender == user_id {
+                continue;
+            }
+
+            let mut msg = Message::new(room, localpart(sender), body, MessageKind::Text);
+            if let Some(ts) = event["origin_server_ts"].as_u64() {
+                msg.time = UNIX_EPOCH + Duration::from_millis(ts);
+            }
+            msg.id = event["event_id"].as_str().map(String::from);
+            msg.direct = direct.contains(room);
+            msg.server = Some(ServerType::Matrix);
+            msg.account = Some(sender.to_string());
+            msg.raw = Some(event.to_string());
+            messages.push(msg);
+        }
+    }
+    messages
+}
+
+/// @alice:example.org -> alice
+fn localpart(user_id: &str) -> &str {
+    let user_id = user_id.strip_prefix('@').unwrap_or(user_id);
+    user_id.split(':').next().unwrap_or(user_id)
+}
+
+/// percent-encode a path segment or query value
+fn encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::server::stub::{self, Reply};
+
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("!abc:example.org"), "%21abc%3Aexample.org");
+        assert_eq!(encode("#foo:example.org"), "%23foo%3Aexample.org");
+        assert_eq!(localpart("@alice:example.org"), "alice");
+    }
+
+    #[test]
+    fn test_direct() {
+        let event = |body: &str| {
+            json!({
+                "type": "m.room.message",
+                "sender": "@alice:local",
+                "content": { "msgtype": "m.text", "body": body },
+            })
+        };
+        let timeline = json!({
+            "!dm:local": { "timeline": { "events": [event("dm")] } },
+            "!invited:local": { "timeline": { "events": [event("invited")] } },
+            // two members but not a direct chat
+            "!pair:local": {
+                "summary": { "m.joined_member_count": 2 },
+                "timeline": { "events": [event("pair")] },
+            },
+        });
+        let mut direct = DirectRooms::default();
+        direct.update(
+            "@hongbot:local",
+            &json!({
+                "account_data": { "events": [{
+                    "type": "m.direct",
+                    "content": { "@alice:local": ["!dm:local"] },
+                }]},
+                "rooms": { "invite": { "!invited:local": { "invite_state": { "events": [{
+                    "type": "m.room.member",
+                    "state_key": "@hongbot:local",
+                    "content": { "membership": "invite", "is_direct": true },
+                }]}}}},
+            }),
+        );
+        // later syncs without the account data keep it
+        direct.update("@hongbot:local", &json!({ "next_batch": "s2" }));
+
+        let resp = json!({ "rooms": { "join": timeline } });
+        let mut messages = to_messages("@hongbot:local", &direct, &resp)
+            .into_iter()
+            .map(|m| (m.message, m.direct))
+            .collect::<Vec<(String, bool)>>();
+        messages.sort();
+        assert_eq!(
+            messages,
+            vec![
+                ("dm".to_string(), true),
+                ("invited".to_string(), true),
+                ("pair".to_string(), false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        // local stand-in homeserver serving canned sync responses
+        let (req_tx, mut req_rx) = unbounded_channel::<(String, String, Value)>();
+        let addr = stub::http(move |req| {
+            if req.url.ends_with("/account/whoami") {
+                json!({ "user_id": "@hongbot:local" })
+            } else if req.url.contains("/sync?timeout=0") {
+                json!({
+                    "next_batch": "s1",
+                    "rooms": {
+                        "invite": { "!room:local": {} },
+                        "join": { "!old:local": { "timeline": { "events": [{
+                            "type": "m.room.message",
+                            "sender": "@alice:local",
+                            "content": { "msgtype": "m.text", "body": "backlog" },
+                        }]}}},
+                    },
+                })
+            } else if req.url.contains("since=s1") {
+                json!({
+                    "next_batch": "s2",
+                    "rooms": { "join": { "!room:local": { "timeline": { "events": [
+                        {
+                            "type": "m.room.message",
+                            "sender": "@hongbot:local",
+                            "content": { "msgtype": "m.text", "body": "own message" },
+                        },
+                        {
+                            "type": "m.room.message",
+                            "sender": "@alice:local",
+                            "content": { "msgtype": "m.text", "body": "hongbot: ping" },
+                        },
+                    ]}}}},
+                })
+            } else if req.url.contains("/sync") {
+                thread::sleep(Duration::from_millis(100));
+                json!({ "next_batch": "s2" })
+            } else {
+                req_tx.send((req.method, req.url, req.body)).unwrap();
+                json!({ "room_id": "!room:local", "event_id": "$1" })
+            }
+        });
+
+        let matrix = Matrix::new(MatrixConfig {
+            homeserver: format!("http://{}", addr),
+            access_token: "secret".to_string(),
+            rooms: None,
+        });
+        let (tx, mut rx) = unbounded_channel();
+        let handle = matrix.connect(tx).await.unwrap();
+
+        // joins on invite
+        let (method, url, _) = stub::recv(&mut req_rx).await;
+        assert_eq!(method, "POST");
+        assert_eq!(url, "/_matrix/client/v3/join/%21room%3Alocal");
+
+        let msg = stub::recv_message(&mut rx, "!room:local", "alice", "hongbot: ping").await;
+
+        matrix.send(&msg.channel, "alice: pong").await.unwrap();
+        let (method, url, body) = stub::recv(&mut req_rx).await;
+        assert_eq!(method, "PUT");
+        assert!(url.starts_with("/_matrix/client/v3/rooms/%21room%3Alocal/send/m.room.message/"));
+        assert_eq!(body["msgtype"], "m.text");
+        assert_eq!(body["body"], "alice: pong");
+
+        matrix.disconnect().await;
+        stub::join(handle).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_fail() {
+        let (sync_tx, mut sync_rx) = unbounded_channel::<()>();
+        let addr = stub::http(move |req| {
+            if req.url.ends_with("/account/whoami") {
+                return Reply(200, json!({ "user_id": "@hongbot:local" }));
+            }
+            sync_tx.send(()).unwrap();
+            Reply(502, json!({}))
+        });
+
+        let matrix = Matrix::new(MatrixConfig {
+            homeserver: format!("http://{}", addr),
+            access_token: "secret".to_string(),
+            rooms: None,
+        });
+        let (tx, _rx) = unbounded_channel();
+        let handle = matrix.connect(tx).await.unwrap();
+
+        stub::recv(&mut sync_rx).await;
+        // the next attempt waits
+        let again = tokio::time::timeout(Duration::from_millis(500), sync_rx.recv()).await;
+        assert!(again.is_err());
+
+        // without waiting out the delay
+        matrix.disconnect().await;
+        tokio::time::timeout(Duration::from_millis(500), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::MattermostConfig,
+};
+
+use super::{
+    net::{request, send_error, stopped, ws_connect, Backoff},
+    Capabilities, SendError, SendResult, Server,
+};
+
+// characters per post
+const MAX_MESSAGE: usize = 16383;
+
+/// Mattermost adapter, events over the websocket and posting with REST.
+///
+/// A reply in a thread is mapped to the channel `<channel_id>:<root_id>`,
+/// and messages in direct channels are treated as addressed to the bot.
+#[derive(Debug)]
+pub struct Mattermost {
+    name: String,
+    config: MattermostConfig,
+    accepted: watch::Sender<bool>,
+}
+
+#[derive(Debug, Error)]
+enum MattermostError {
+    #[error("mattermost api error: {0} {1}")]
+    Api(u16, Value),
+}
+
+#[derive(Clone, Debug)]
+struct Api {
+    url: String,
+    token: String,
+}
+
+impl Api {
+    async fn call(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+        let url = format!("{}/api/v4{}", self.url, path);
+        let headers = [format!("Authorization: Bearer {}", self.token)];
+        let (code, resp) = request(method, &url, &headers, body).await?;
+        if !(200..300).contains(&code) {
+            return Err(MattermostError::Api(code, resp).into());
+        }
+        Ok(resp)
+    }
+
+    fn websocket_url(&self) -> String {
+        let url = if let Some(rest) = self.url.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else if let Some(rest) = self.url.strip_prefix("http://") {
+            format!("ws://{}", rest)
+        } else {
+            self.url.clone()
+        };
+        format!("{}/api/v4/websocket", url)
+    }
+}
+
+impl Mattermost {
+    pub fn new(name: String, config: MattermostConfig) -> Self {
+        Mattermost {
+            name,
+            config,
+            accepted: watch::channel(false).0,
+        }
+    }
+
+    fn api(&self) -> Api {
+        Api {
+            url: self.config.url.trim_end_matches('/').to_string(),
+            token: self.config.token.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Mattermost {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let api = self.api();
+        let me = api.call("GET", "/users/me", None).await?;
+        let user_id = me["id"].as_str().unwrap_or_default().to_string();
+        let username = me["username"].as_str().unwrap_or_default().to_string();
+        log::trace!("logged in as @{}", username);
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let name = self.name.clone();
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            while *accepted.borrow() {
+                let mut socket = match ws_connect(&api.websocket_url()).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::error!("websocket connect fail: {e}");
+                        backoff.wait(&mut accepted).await;
+                        continue;
+                    }
+                };
+                log::trace!("Connected to the server!");
+
+                let challenge = json!({
+                    "seq": 1,
+                    "action": "authentication_challenge",
+                    "data": { "token": api.token },
+                });
+                if let Err(e) = socket.send(WsMessage::Text(challenge.to_string())).await {
+                    log::error!("authentication fail: {e}");
+                    backoff.wait(&mut accepted).await;
+                    continue;
+                }
+
+                loop {
+                    let msg = tokio::select! {
+                        _ = stopped(&mut accepted) => {
+                            socket.close(None).await.ok();
+                            break;
+                        }
+                        msg = socket.next() => msg,
+                    };
+                    let text = match msg {
+                        Some(Ok(WsMessage::Text(text))) => text,
+                        Some(Ok(WsMessage::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => {
+                            log::error!("read fail: {e}");
+                            break;
+                        }
+                    };
+
+                    let event: Value = match serde_json::from_str(&text) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            log::error!("unexpected event: {e}");
+                            continue;
+                        }
+                    };
+                    // reply to the challenge, a rejected token is closed
+                    if event["seq_reply"] == 1 {
+                        match event["status"].as_str() {
+                            Some("OK") => backoff.reset(),
+                            _ => log::error!("authentication fail: {}", text),
+                        }
+                        continue;
+                    }
+                    if event["event"] != "posted" {
+                        log::trace!("{}", text);
+                        continue;
+                    }
+                    if let Some(msg) = to_message(&name, &username, &user_id, &event["data"]) {
+                        if tx.send(msg).is_err() {
+                            log::error!("receiver dropped, stop reading");
+                            socket.close(None).await.ok();
+                            return;
+                        }
+                    }
+                }
+                backoff.wait(&mut accepted).await;
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        if message.chars().count() > MAX_MESSAGE {
+            return Err(SendError::TooLong(MAX_MESSAGE));
+        }
+        let (channel_id, root_id) = channel.split_once(':').unwrap_or((channel, ""));
+        let post = json!({
+            "channel_id": channel_id,
+            "message": message,
+            "root_id": root_id,
+        });
+        self.api()
+            .call("POST", "/posts", Some(&post))
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                send_error(
+                    e,
+                    |MattermostError::Api(code, _): &MattermostError| match code {
+                        429 => Some(SendError::RateLimited(None)),
+                        403 | 404 => Some(SendError::UnknownTarget(channel_id.to_string())),
+                        _ => None,
+                    },
+                )
+            })
+    }
+
+    async fn send_thread_reply(&self, channel: &str, thread: &str, message: &str) -> SendResult {
+        let (channel_id, _) = channel.split_once(':').unwrap_or((channel, ""));
+        self.send(&format!("{}:{}", channel_id, thread), message)
+            .await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            threads: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+fn to_message(name: &str, username: &str, user_id: &str, data: &Value) -> Option<Message> {
+    // the post is a json encoded string
+    let post: Value = serde_json::from_str(data["post"].as_str()?).ok()?;
+    if post["user_id"] == user_id {
+        return None;
+    }
+
+    let channel_id = post["channel_id"].as_str()?;
+    let channel = match post["root_id"].as_str() {
+        Some(root_id) if !root_id.is_empty() => format!("{}:{}", channel_id, root_id),
+        _ => channel_id.to_string(),
+    };
+    let nick = data["sender_name"].as_str()?.trim_start_matches('@');
+
+    let text = post["message"].as_str().unwrap_or_default();
+    let mention = format!("@{}", username);
+    let message = match text.strip_prefix(&mention) {
+        Some(rest) => format!("{}{}", name, rest),
+        None if data["channel_type"] == "D" && !text.starts_with(name) => {
+            format!("{} {}", name, text)
+        }
+        None => text.to_string(),
+    };
+
+    let mut msg = Message::new(&channel, nick, &message, MessageKind::Text);
+    if let Some(create_at) = post["create_at"].as_u64() {
+        msg.time = UNIX_EPOCH + Duration::from_millis(create_at);
+    }
+    msg.id = post["id"].as_str().map(String::from);
+    msg.direct = data["channel_type"] == "D";
+    msg.server = Some(ServerType::Mattermost);
+    msg.account = post["user_id"].as_str().map(String::from);
+    msg.raw = Some(data.to_string());
+    Some(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::server::stub::{self, respond};
+
+    use super::*;
+
+    fn posted(channel_type: &str, post: Value) -> Value {
+        json!({
+            "event": "posted",
+            "data": {
+                "channel_type": channel_type,
+                "sender_name": "@alice",
+                "post": post.to_string(),
+            },
+        })
+    }
+
+    #[test]
+    fn test_to_message() {
+        let data = &posted(
+            "O",
+            json!({ "user_id": "u1", "channel_id": "c1", "root_id": "", "message": "@hongbot-bot ping" }),
+        )["data"];
+        let msg = to_message("hongbot", "hongbot-bot", "me", data).unwrap();
+        assert_eq!(msg.channel, "c1");
+        assert_eq!(msg.nick, "alice");
+        assert_eq!(msg.message, "hongbot ping");
+
+        let data = &posted(
+            "D",
+            json!({ "user_id": "u1", "channel_id": "d1", "root_id": "p1", "message": "ping" }),
+        )["data"];
+        let msg = to_message("hongbot", "hongbot-bot", "me", data).unwrap();
+        assert_eq!(msg.channel, "d1:p1");
+        assert_eq!(msg.message, "hongbot ping");
+
+        let data = &posted(
+            "O",
+            json!({ "user_id": "me", "channel_id": "c1", "message": "pong" }),
+        )["data"];
+        assert!(to_message("hongbot", "hongbot-bot", "me", data).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        // local stand-in serving both REST and the websocket
+        let (listener, addr) = stub::listen();
+
+        let (req_tx, mut req_rx) = unbounded_channel::<(String, Value)>();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut buf = [0; 64];
+                let n = stream.peek(&mut buf).unwrap();
+                let head = String::from_utf8_lossy(&buf[..n]).to_string();
+
+                if head.starts_with("GET /api/v4/websocket") {
+                    let req_tx = req_tx.clone();
+                    thread::spawn(move || {
+                        let mut ws = tungstenite::accept(stream).unwrap();
+                        let challenge = stub::read_json(&mut ws);
+                        req_tx.send(("websocket".to_string(), challenge)).unwrap();
+                        let post = json!({
+                            "id": "p2",
+                            "user_id": "u1",
+                            "channel_id": "c1",
+                            "root_id": "p1",
+                            "message": "@hongbot-bot ping",
+                        });
+                        stub::write_json(&mut ws, &posted("O", post));
+                        while ws.read_message().is_ok() {}
+                    });
+                } else if head.starts_with("GET /api/v4/users/me") {
+                    respond(stream, &json!({ "id": "me", "username": "hongbot-bot" }));
+                } else {
+                    let req = respond(stream, &json!({ "id": "p3" }));
+                    req_tx.send(req).unwrap();
+                }
+            }
+        });
+
+        let mattermost = Mattermost::new(
+            "hongbot".to_string(),
+            MattermostConfig {
+                url: format!("http://{}", addr),
+                token: "secret".to_string(),
+            },
+        );
+        let (tx, mut rx) = unbounded_channel();
+        let handle = mattermost.connect(tx).await.unwrap();
+
+        let (kind, challenge) = stub::recv(&mut req_rx).await;
+        assert_eq!(kind, "websocket");
+        assert_eq!(challenge["action"], "authentication_challenge");
+        assert_eq!(challenge["data"]["token"], "secret");
+
+        let msg = stub::recv_message(&mut rx, "c1:p1", "alice", "hongbot ping").await;
+
+        mattermost.send(&msg.channel, "alice: pong").await.unwrap();
+        let (request_line, body) = stub::recv(&mut req_rx).await;
+        assert!(request_line.starts_with("POST /api/v4/posts"));
+        assert_eq!(body["channel_id"], "c1");
+        assert_eq!(body["root_id"], "p1");
+        assert_eq!(body["message"], "alice: pong");
+
+        mattermost.disconnect().await;
+        stub::join(handle).await;
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token() {
+        let (listener, addr) = stub::listen();
+
+        let (conn_tx, mut conn_rx) = unbounded_channel::<()>();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut buf = [0; 64];
+                let n = stream.peek(&mut buf).unwrap();
+                if !buf[..n].starts_with(b"GET /api/v4/websocket") {
+                    respond(stream, &json!({ "id": "me", "username": "hongbot-bot" }));
+                    continue;
+                }
+                conn_tx.send(()).unwrap();
+                let mut ws = tungstenite::accept(stream).unwrap();
+                ws.read_message().unwrap();
+                let fail = json!({ "status": "FAIL", "seq_reply": 1 });
+                stub::write_json(&mut ws, &fail);
+                ws.close(None).unwrap();
+                while ws.read_message().is_ok() {}
+            }
+        });
+
+        let mattermost = Mattermost::new(
+            "hongbot".to_string(),
+            MattermostConfig {
+                url: format!("http://{}", addr),
+                token: "bad".to_string(),
+            },
+        );
+        let (tx, _rx) = unbounded_channel();
+        let handle = mattermost.connect(tx).await.unwrap();
+
+        stub::recv(&mut conn_rx).await;
+        // the next attempt waits
+        let again = tokio::time::timeout(Duration::from_millis(500), conn_rx.recv()).await;
+        assert!(again.is_err());
+
+        mattermost.disconnect().await;
+        stub::join(handle).await;
+    }
+}
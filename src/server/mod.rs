@@ -1,15 +1,107 @@
-use std::{sync::mpsc::Sender, thread::JoinHandle};
+use std::time::Duration;
 
 use anyhow::Result;
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
 
-use crate::bot::Message;
+use crate::{bot::Message, http::Route};
 
+pub mod discord;
 pub mod irc;
+pub mod matrix;
+pub mod mattermost;
+pub(crate) mod net;
 pub mod shell;
+pub mod slack;
+/// local stand-ins for the services the adapter tests talk to
+#[cfg(test)]
+mod stub;
+pub mod telegram;
+pub mod test;
+pub mod webhook;
+pub mod xmpp;
 
-pub trait Server {
+/// why a message could not be delivered
+#[derive(Debug, Error)]
+pub enum SendError {
+    #[error("not connected")]
+    Disconnected,
+    /// retry after the duration if the server told
+    #[error("rate limited")]
+    RateLimited(Option<Duration>),
+    /// limit of the server in bytes or characters
+    #[error("message too long, limit is {0}")]
+    TooLong(usize),
+    #[error("unknown target: {0}")]
+    UnknownTarget(String),
+    /// the adapter cannot do this, see `Server::capabilities`
+    #[error("not supported")]
+    Unsupported,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type SendResult = std::result::Result<(), SendError>;
+
+/// optional features of an adapter, see `Server::capabilities`
+///
+/// methods of unsupported features fall back to `send` or fail with
+/// `SendError::Unsupported`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub emote: bool,
+    pub notice: bool,
+    pub topic: bool,
+    pub join: bool,
+    pub part: bool,
+    pub react: bool,
+    pub threads: bool,
+}
+
+#[async_trait]
+pub trait Server: Send + Sync {
     /// connect tx is message channel sender that from server to bot
-    fn connect(&mut self, tx: Sender<Message>) -> Result<JoinHandle<()>>;
-    fn disconnect(&mut self);
-    fn send(&mut self, channel: &str, message: &str);
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>>;
+    async fn disconnect(&self);
+    async fn send(&self, channel: &str, message: &str) -> SendResult;
+    /// message addressed to nick
+    async fn reply(&self, channel: &str, nick: &str, message: &str) -> SendResult {
+        self.send(channel, &format!("{}: {}", nick, message)).await
+    }
+    /// action like `/me waves`
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        self.send(channel, &format!("* {}", action)).await
+    }
+    /// message that should not trigger automatic responses
+    async fn notice(&self, channel: &str, message: &str) -> SendResult {
+        self.send(channel, message).await
+    }
+    async fn topic(&self, _channel: &str, _topic: &str) -> SendResult {
+        Err(SendError::Unsupported)
+    }
+    async fn join(&self, _channel: &str) -> SendResult {
+        Err(SendError::Unsupported)
+    }
+    async fn part(&self, _channel: &str) -> SendResult {
+        Err(SendError::Unsupported)
+    }
+    /// reaction such as an emoji to the message `id` in channel
+    async fn react(&self, _channel: &str, _id: &str, _reaction: &str) -> SendResult {
+        Err(SendError::Unsupported)
+    }
+    /// reply in the thread started by the message `thread`
+    async fn send_thread_reply(&self, channel: &str, _thread: &str, message: &str) -> SendResult {
+        self.send(channel, message).await
+    }
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+    /// watch nicks, presence changes are sent to bot as Online/Offline messages
+    async fn monitor(&self, _nicks: &[String]) {}
+    async fn unmonitor(&self, _nicks: &[String]) {}
+    /// endpoints to serve on the builtin http server, called before connect
+    fn routes(&self) -> Vec<Route> {
+        Vec::new()
+    }
 }
@@ -0,0 +1,132 @@
+use std::{sync::OnceLock, time::Duration};
+
+use anyhow::Result;
+use reqwest::{Client, Method};
+use serde_json::Value;
+use tokio::{net::TcpStream, sync::watch, time::sleep};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use super::SendError;
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// seconds between reconnects
+const BACKOFF_MIN: u64 = 1;
+const BACKOFF_MAX: u64 = 60;
+
+/// shared client so connections are pooled between calls
+pub fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(Client::new)
+}
+
+/// send a http request with an optional json body, returns status code and json response
+pub async fn request(
+    method: &str,
+    url: &str,
+    headers: &[String],
+    body: Option<&Value>,
+) -> Result<(u16, Value)> {
+    let mut req = client().request(Method::from_bytes(method.as_bytes())?, url);
+    for header in headers {
+        if let Some((name, value)) = header.split_once(':') {
+            req = req.header(name.trim(), value.trim());
+        }
+    }
+    if let Some(body) = body {
+        req = req.json(body);
+    }
+
+    let resp = req.send().await?;
+    let code = resp.status().as_u16();
+    let buf = resp.bytes().await?;
+    // non-json bodies (e.g. error pages) are returned as a string
+    let value = if buf.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&buf)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&buf).to_string()))
+    };
+    Ok((code, value))
+}
+
+/// send error for a failed api call, `api` maps the adapter's own api errors
+pub fn send_error<E, F>(e: anyhow::Error, api: F) -> SendError
+where
+    E: std::error::Error + Send + Sync + 'static,
+    F: FnOnce(&E) -> Option<SendError>,
+{
+    if let Some(err) = e.downcast_ref::<E>().and_then(api) {
+        return err;
+    }
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(err) if err.is_connect() => SendError::Disconnected,
+        _ => SendError::Other(e),
+    }
+}
+
+pub async fn ws_connect(url: &str) -> Result<Socket> {
+    let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+    Ok(socket)
+}
+
+/// resolves once `accepted` is turned off by disconnect
+pub async fn stopped(accepted: &mut watch::Receiver<bool>) {
+    // the guard returned by wait_for must not be held across an await
+    let _ = accepted.wait_for(|accepted| !*accepted).await;
+}
+
+/// Delay before reconnecting, doubles on every attempt up to a minute.
+///
+/// Adapters reset it once the server accepted the session, so a healthy
+/// connection that drops reconnects quickly while a rejected one backs off.
+#[derive(Debug)]
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            delay: Duration::from_secs(BACKOFF_MIN),
+        }
+    }
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        *self = Backoff::default();
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Duration::from_secs(BACKOFF_MAX));
+        delay
+    }
+
+    /// sleeps the delay, false if disconnected meanwhile
+    pub async fn wait(&mut self, accepted: &mut watch::Receiver<bool>) -> bool {
+        let delay = self.next();
+        log::trace!("reconnecting in {:?}", delay);
+        tokio::select! {
+            _ = stopped(accepted) => false,
+            _ = sleep(delay) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        let delays = (0..8)
+            .map(|_| backoff.next().as_secs())
+            .collect::<Vec<u64>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+}
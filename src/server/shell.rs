@@ -1,69 +1,297 @@
 use std::{
-    cmp::Ordering,
-    io::{self, stdout, Write},
-    sync::{mpsc::Sender, Arc, RwLock},
-    thread::{self, JoinHandle},
+    io::{self, IsTerminal},
+    sync::{Arc, Mutex, RwLock},
+    thread,
     time::Duration,
 };
 
 use anyhow::Result;
+use async_trait::async_trait;
+use rustyline::{error::ReadlineError, Editor, ExternalPrinter};
+use tokio::{
+    fs::File,
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    sync::{mpsc::UnboundedSender, watch},
+    task::{self, JoinHandle},
+};
 
-use crate::bot::Message;
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::ShellConfig,
+};
 
-use super::Server;
+use super::{net::stopped, Capabilities, SendError, SendResult, Server};
 
-#[derive(Debug)]
 pub struct Shell {
     name: String,
-    tx: Option<Sender<Message>>,
-    accepted: Option<Arc<RwLock<bool>>>,
-    width: usize,
+    config: ShellConfig,
+    batch: bool,
+    accepted: watch::Sender<bool>,
+    session: Arc<RwLock<Session>>,
+    printer: Arc<Mutex<Option<Box<dyn ExternalPrinter + Send>>>>,
 }
 
-impl Shell {
-    pub fn new(name: String) -> Self {
-        let width = match &name.len().cmp(&SHELL_SERVER_NICK.len()) {
-            Ordering::Equal => SHELL_SERVER_NICK.len(),
-            Ordering::Less => SHELL_SERVER_NICK.len(),
-            Ordering::Greater => name.len(),
+/// simulated user, changed by slash commands
+#[derive(Debug)]
+struct Session {
+    nick: String,
+    channel: String,
+}
+
+impl Session {
+    /// applies a line of input, returns the message to send if any
+    fn input(&mut self, name: &str, line: &str) -> Option<Message> {
+        let (channel, message, kind) = match Input::parse(line) {
+            Input::Say(text) => (Some(self.channel.as_str()), text, MessageKind::Text),
+            Input::Me(action) => (Some(self.channel.as_str()), action, MessageKind::Emote),
+            Input::Msg(target, text) if target == name => (None, text, MessageKind::Text),
+            Input::Msg(target, _) => {
+                eprintln!("no such nick: {}", target);
+                return None;
+            }
+            Input::Nick(nick) => {
+                self.nick = nick;
+                return None;
+            }
+            Input::Join(channel) => {
+                self.channel = channel;
+                return None;
+            }
+            Input::Help => {
+                eprintln!("{}", HELP);
+                return None;
+            }
+            Input::Unknown(line) => {
+                eprintln!("unknown command: {}, try /help", line);
+                return None;
+            }
         };
+        let mut msg = to_message(name, &self.nick, channel, &message, kind);
+        msg.raw = Some(line.to_string());
+        Some(msg)
+    }
+}
 
+/// message from `nick` to the bot `name`, on `channel` or direct when it is
+/// None, direct messages are addressed to the bot and answered to the sender
+pub(crate) fn to_message(
+    name: &str,
+    nick: &str,
+    channel: Option<&str>,
+    text: &str,
+    kind: MessageKind,
+) -> Message {
+    let mut msg = match channel {
+        Some(channel) => Message::new(channel, nick, text, kind),
+        None => {
+            let text = if text.starts_with(name) {
+                text.to_string()
+            } else {
+                format!("{} {}", name, text)
+            };
+            let mut msg = Message::new(nick, nick, &text, kind);
+            msg.direct = true;
+            msg
+        }
+    };
+    msg.server = Some(ServerType::Shell);
+    msg
+}
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Say(String),
+    /// /nick alice
+    Nick(String),
+    /// /join #ops
+    Join(String),
+    /// /msg hongbot ping
+    Msg(String, String),
+    /// /me waves
+    Me(String),
+    Help,
+    Unknown(String),
+}
+
+impl Input {
+    fn parse(line: &str) -> Self {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let Some(command) = line.strip_prefix('/') else {
+            return Input::Say(line.to_string());
+        };
+
+        let (cmd, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+        match (cmd, args) {
+            ("nick", nick) if !nick.is_empty() && !nick.contains(' ') => {
+                Input::Nick(nick.to_string())
+            }
+            ("join", channel) if !channel.is_empty() && !channel.contains(' ') => {
+                let channel = if channel.starts_with('#') {
+                    channel.to_string()
+                } else {
+                    format!("#{}", channel)
+                };
+                Input::Join(channel)
+            }
+            ("msg", args) => match args.split_once(' ') {
+                Some((target, text)) => Input::Msg(target.to_string(), text.to_string()),
+                None => Input::Unknown(line.to_string()),
+            },
+            ("me", action) if !action.is_empty() => Input::Me(action.to_string()),
+            ("help", _) => Input::Help,
+            _ => Input::Unknown(line.to_string()),
+        }
+    }
+}
+
+impl Shell {
+    pub fn new(name: String, config: ShellConfig) -> Self {
+        let batch = config.input.is_some() || !io::stdin().is_terminal();
         Shell {
             name,
-            accepted: None,
-            tx: None,
-            width,
+            config,
+            batch,
+            accepted: watch::channel(false).0,
+            session: Arc::new(RwLock::new(Session {
+                nick: SHELL_SERVER_NICK.to_string(),
+                channel: SHELL_SERVER_CHANNEL.to_string(),
+            })),
+            printer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn print(&self, channel: &str, message: &str) -> SendResult {
+        // batch output is meant for scripts, print it as is
+        if self.batch {
+            println!("{}", message);
+            return Ok(());
         }
+
+        let width = self.name.len().max(self.session.read().unwrap().nick.len());
+        let line = format!("{:>width$}{}> {}", self.name, target(channel), message);
+        match self.printer.lock().unwrap().as_mut() {
+            Some(printer) => printer
+                .print(format!("{}\n", line))
+                .map_err(|e| SendError::Other(e.into()))?,
+            None => println!("{}", line),
+        }
+        Ok(())
+    }
+
+    /// reads commands until EOF, the bot stops once `tx` is dropped
+    async fn connect_batch(
+        &self,
+        tx: UnboundedSender<Message>,
+        mut accepted: watch::Receiver<bool>,
+    ) -> Result<JoinHandle<()>> {
+        let input: Box<dyn AsyncBufRead + Send + Unpin> = match &self.config.input {
+            Some(path) => Box::new(BufReader::new(File::open(path).await?)),
+            None => Box::new(BufReader::new(tokio::io::stdin())),
+        };
+
+        let name = self.name.clone();
+        let session = self.session.clone();
+        let handle = tokio::spawn(async move {
+            let mut lines = input.lines();
+            loop {
+                let line = tokio::select! {
+                    _ = stopped(&mut accepted) => break,
+                    line = lines.next_line() => line,
+                };
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::error!("read fail: {e}");
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let msg = session.write().unwrap().input(&name, &line);
+                if let Some(msg) = msg {
+                    if tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+            }
+            log::trace!("end of input");
+        });
+
+        Ok(handle)
+    }
+}
+
+/// channel as prompts show it, `#ops`, or `@alice` for direct messages
+/// whose channel is the peer's nick
+pub(crate) fn target(channel: &str) -> String {
+    if channel.starts_with('#') {
+        channel.to_string()
+    } else {
+        format!("@{}", channel)
     }
 }
 
 const SHELL_SERVER_CHANNEL: &str = "#shell";
 const SHELL_SERVER_NICK: &str = "you";
+const HISTORY_FILE: &str = ".shell_history";
+const HELP: &str = "\
+/nick <nick>         change your nick
+/join <channel>      switch to channel
+/msg <nick> <text>   send a direct message
+/me <action>         send an action";
 
+#[async_trait]
 impl Server for Shell {
-    fn connect(&mut self, tx: Sender<Message>) -> Result<JoinHandle<()>> {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
         log::trace!("connect");
-        let lock0 = Arc::new(RwLock::new(true));
-        let lock1 = Arc::clone(&lock0);
-        self.accepted = Some(lock0);
-        self.tx = Some(tx.clone());
-        let width = self.width;
-        let handle = thread::spawn(move || {
-            let stdin = io::stdin();
+        self.accepted.send_replace(true);
+        let accepted = self.accepted.subscribe();
+
+        if self.batch {
+            return self.connect_batch(tx, accepted).await;
+        }
+
+        let mut rl = Editor::<()>::new()?;
+        rl.load_history(HISTORY_FILE).ok();
+        if let Ok(printer) = rl.create_external_printer() {
+            *self.printer.lock().unwrap() = Some(Box::new(printer));
+        }
+
+        let name = self.name.clone();
+        let session = self.session.clone();
+        // readline blocks, keep it off the async workers
+        let handle = task::spawn_blocking(move || {
             let dur = Duration::from_millis(10);
-            let mut stdout = stdout();
-            let mut buf = String::new();
-            while *(lock1.read().expect("acquire read lock fail")) {
-                print!("{:>width$}{}> ", SHELL_SERVER_NICK, SHELL_SERVER_CHANNEL);
-                stdout.flush().unwrap();
-                stdin.read_line(&mut buf).expect("read fail");
-                tx.send(Message {
-                    channel: SHELL_SERVER_CHANNEL.to_string(),
-                    nick: SHELL_SERVER_NICK.to_string(),
-                    message: buf.clone(),
-                })
-                .expect("send fail");
-                buf.clear();
+            while *accepted.borrow() {
+                let prompt = {
+                    let session = session.read().unwrap();
+                    let width = name.len().max(session.nick.len());
+                    format!("{:>width$}{}> ", session.nick, target(&session.channel))
+                };
+                let line = match rl.readline(&prompt) {
+                    Ok(line) => line,
+                    Err(ReadlineError::Interrupted) => continue,
+                    // dropping tx on EOF stops the bot
+                    Err(ReadlineError::Eof) => break,
+                    Err(e) => {
+                        log::error!("read fail: {e}");
+                        break;
+                    }
+                };
+                if !line.trim().is_empty() {
+                    rl.add_history_entry(line.as_str());
+                    rl.save_history(HISTORY_FILE).ok();
+                }
+
+                let msg = session.write().unwrap().input(&name, &line);
+                if let Some(msg) = msg {
+                    if tx.send(msg).is_err() {
+                        break;
+                    }
+                }
 
                 // sleep 을 주지 않으면 disconnect 에 의해 accepted 값이
                 // 변경되기 전에 loop 로 들어와서 표준입력을 기다림 -> 뭐라도 눌러야 종료되는 상황
@@ -74,16 +302,97 @@ impl Server for Shell {
         Ok(handle)
     }
 
-    fn disconnect(&mut self) {
+    async fn disconnect(&self) {
         log::trace!("disconnect");
-        if let Some(lock) = &self.accepted {
-            let mut lock = lock.write().expect("acquire write lock fail");
-            *lock = false;
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        self.print(channel, message)
+    }
+
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        self.print(channel, &format!("* {} {}", self.name, action))
+    }
+
+    async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        self.print(
+            channel,
+            &format!("* {} changed the topic to: {}", self.name, topic),
+        )
+    }
+
+    async fn join(&self, channel: &str) -> SendResult {
+        self.print(channel, &format!("* {} has joined {}", self.name, channel))
+    }
+
+    async fn part(&self, channel: &str) -> SendResult {
+        self.print(channel, &format!("* {} has left {}", self.name, channel))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            emote: true,
+            topic: true,
+            join: true,
+            part: true,
+            ..Capabilities::default()
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_parse() {
+        assert_eq!(
+            Input::parse("hongbot: ping\n"),
+            Input::Say("hongbot: ping".to_string())
+        );
+        assert_eq!(
+            Input::parse("/nick alice"),
+            Input::Nick("alice".to_string())
+        );
+        assert_eq!(Input::parse("/join ops"), Input::Join("#ops".to_string()));
+        assert_eq!(Input::parse("/join #ops"), Input::Join("#ops".to_string()));
+        assert_eq!(
+            Input::parse("/msg hongbot foo is bar"),
+            Input::Msg("hongbot".to_string(), "foo is bar".to_string())
+        );
+        assert_eq!(Input::parse("/me waves"), Input::Me("waves".to_string()));
+        assert_eq!(Input::parse("/help"), Input::Help);
+        assert_eq!(Input::parse("/nick"), Input::Unknown("/nick".to_string()));
+        assert_eq!(
+            Input::parse("/msg hongbot"),
+            Input::Unknown("/msg hongbot".to_string())
+        );
+    }
+
+    #[test]
+    fn test_session_input() {
+        let mut session = Session {
+            nick: SHELL_SERVER_NICK.to_string(),
+            channel: SHELL_SERVER_CHANNEL.to_string(),
+        };
+        let msg = session.input("hongbot", "hongbot: ping").unwrap();
+        assert_eq!(msg.channel, "#shell");
+        assert_eq!(msg.nick, "you");
+        assert_eq!(msg.kind, MessageKind::Text);
+
+        assert!(session.input("hongbot", "/nick alice").is_none());
+        assert!(session.input("hongbot", "/join ops").is_none());
+        let msg = session.input("hongbot", "/me waves").unwrap();
+        assert_eq!(msg.channel, "#ops");
+        assert_eq!(msg.nick, "alice");
+        assert_eq!(msg.message, "waves");
+        assert_eq!(msg.kind, MessageKind::Emote);
 
-    fn send(&mut self, channel: &str, message: &str) {
-        let width = self.width;
-        println!("{:>width$}{}> {}", self.name, channel, message);
+        let msg = session.input("hongbot", "/msg hongbot ping").unwrap();
+        assert_eq!(msg.channel, "alice");
+        assert_eq!(msg.message, "hongbot ping");
+        assert!(msg.direct);
+        assert!(session.input("hongbot", "/msg bob ping").is_none());
     }
 }
@@ -0,0 +1,571 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::SlackConfig,
+};
+
+use super::{
+    net::{request, send_error, stopped, ws_connect, Backoff, Socket},
+    Capabilities, SendError, SendResult, Server,
+};
+
+const API_URL: &str = "https://slack.com/api";
+// characters, chat.postMessage truncates text beyond this
+const MAX_MESSAGE: usize = 40000;
+
+/// Slack adapter, events over Socket Mode websocket and posting with Web API.
+///
+/// A message in a thread is mapped to the channel `<channel>:<thread_ts>`,
+/// so replying to it posts into the same thread.
+#[derive(Debug)]
+pub struct Slack {
+    name: String,
+    config: SlackConfig,
+    accepted: watch::Sender<bool>,
+    users: Arc<Mutex<Users>>,
+}
+
+#[derive(Debug, Error)]
+enum SlackError {
+    #[error("slack api error: {0}")]
+    Api(String),
+}
+
+/// user id <-> name cache
+#[derive(Debug, Default)]
+struct Users {
+    names: HashMap<String, String>,
+    ids: HashMap<String, String>,
+}
+
+impl Users {
+    fn insert(&mut self, id: &str, name: &str) {
+        self.names.insert(id.to_string(), name.to_string());
+        self.ids.insert(name.to_string(), id.to_string());
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Api {
+    url: String,
+    app_token: String,
+    bot_token: String,
+}
+
+impl Api {
+    async fn call(&self, method: &str, token: &str, params: &Value) -> Result<Value> {
+        let url = format!("{}/{}", self.url, method);
+        let headers = [format!("Authorization: Bearer {}", token)];
+        let (_, resp) = request("POST", &url, &headers, Some(params)).await?;
+        if resp["ok"].as_bool() != Some(true) {
+            let error = resp["error"].as_str().unwrap_or("unknown").to_string();
+            return Err(SlackError::Api(error).into());
+        }
+        Ok(resp)
+    }
+
+    async fn bot(&self, method: &str, params: &Value) -> Result<Value> {
+        self.call(method, &self.bot_token, params).await
+    }
+
+    async fn app(&self, method: &str, params: &Value) -> Result<Value> {
+        self.call(method, &self.app_token, params).await
+    }
+}
+
+impl Slack {
+    pub fn new(name: String, config: SlackConfig) -> Self {
+        Slack {
+            name,
+            config,
+            accepted: watch::channel(false).0,
+            users: Arc::new(Mutex::new(Users::default())),
+        }
+    }
+
+    fn api(&self) -> Api {
+        Api {
+            url: self
+                .config
+                .api_url
+                .clone()
+                .unwrap_or_else(|| API_URL.to_string()),
+            app_token: self.config.app_token.clone(),
+            bot_token: self.config.bot_token.clone(),
+        }
+    }
+
+    /// web api call on behalf of the bot for an outgoing message or command
+    async fn post(&self, method: &str, channel: &str, params: &Value) -> SendResult {
+        self.api()
+            .bot(method, params)
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                send_error(e, |err: &SlackError| match err {
+                    SlackError::Api(error) => match error.as_str() {
+                        "ratelimited" => Some(SendError::RateLimited(None)),
+                        "msg_too_long" => Some(SendError::TooLong(MAX_MESSAGE)),
+                        "channel_not_found" | "not_in_channel" | "is_archived" => {
+                            Some(SendError::UnknownTarget(channel.to_string()))
+                        }
+                        _ => None,
+                    },
+                })
+            })
+    }
+}
+
+#[async_trait]
+impl Server for Slack {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let api = self.api();
+        let auth = api.bot("auth.test", &json!({})).await?;
+        let user_id = auth["user_id"].as_str().unwrap_or_default().to_string();
+        log::trace!("authenticated as {}", user_id);
+        self.users.lock().unwrap().insert(&user_id, &self.name);
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let name = self.name.clone();
+        let users = self.users.clone();
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            while *accepted.borrow() {
+                let mut socket = match open(&api).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        log::error!("socket mode connect fail: {e}");
+                        backoff.wait(&mut accepted).await;
+                        continue;
+                    }
+                };
+                log::trace!("Connected to the server!");
+
+                loop {
+                    let msg = tokio::select! {
+                        _ = stopped(&mut accepted) => {
+                            socket.close(None).await.ok();
+                            break;
+                        }
+                        msg = socket.next() => msg,
+                    };
+                    let text = match msg {
+                        Some(Ok(WsMessage::Text(text))) => text,
+                        Some(Ok(WsMessage::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => {
+                            log::error!("read fail: {e}");
+                            break;
+                        }
+                    };
+
+                    let envelope: Value = match serde_json::from_str(&text) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            log::error!("unexpected envelope: {e}");
+                            continue;
+                        }
+                    };
+
+                    // every envelope with an id must be acknowledged
+                    if let Some(id) = envelope["envelope_id"].as_str() {
+                        let ack = json!({ "envelope_id": id }).to_string();
+                        if let Err(e) = socket.send(WsMessage::Text(ack)).await {
+                            log::error!("ack fail: {e}");
+                        }
+                    }
+
+                    match envelope["type"].as_str() {
+                        Some("events_api") => {
+                            let event = &envelope["payload"]["event"];
+                            let msg = to_message(&api, &users, &user_id, &name, event).await;
+                            if let Some(msg) = msg {
+                                if tx.send(msg).is_err() {
+                                    log::error!("receiver dropped, stop reading");
+                                    socket.close(None).await.ok();
+                                    return;
+                                }
+                            }
+                        }
+                        Some("hello") => backoff.reset(),
+                        Some("disconnect") => {
+                            log::trace!("disconnect requested, reconnecting");
+                            break;
+                        }
+                        _ => {
+                            log::trace!("{}", text);
+                        }
+                    }
+                }
+                backoff.wait(&mut accepted).await;
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let (channel, thread_ts) = match channel.split_once(':') {
+            Some((channel, ts)) => (channel, Some(ts)),
+            None => (channel, None),
+        };
+        let text = encode(&self.users.lock().unwrap(), message);
+        let mut params = json!({ "channel": channel, "text": text });
+        if let Some(ts) = thread_ts {
+            params["thread_ts"] = json!(ts);
+        }
+        self.post("chat.postMessage", channel, &params).await
+    }
+
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        let (channel, _) = channel.split_once(':').unwrap_or((channel, ""));
+        let text = encode(&self.users.lock().unwrap(), action);
+        let params = json!({ "channel": channel, "text": text });
+        self.post("chat.meMessage", channel, &params).await
+    }
+
+    async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        let (channel, _) = channel.split_once(':').unwrap_or((channel, ""));
+        let params = json!({ "channel": channel, "topic": topic });
+        self.post("conversations.setTopic", channel, &params).await
+    }
+
+    async fn join(&self, channel: &str) -> SendResult {
+        let params = json!({ "channel": channel });
+        self.post("conversations.join", channel, &params).await
+    }
+
+    async fn part(&self, channel: &str) -> SendResult {
+        let params = json!({ "channel": channel });
+        self.post("conversations.leave", channel, &params).await
+    }
+
+    async fn react(&self, channel: &str, id: &str, reaction: &str) -> SendResult {
+        let (channel, _) = channel.split_once(':').unwrap_or((channel, ""));
+        let params = json!({
+            "channel": channel,
+            "timestamp": id,
+            "name": reaction.trim_matches(':'),
+        });
+        self.post("reactions.add", channel, &params).await
+    }
+
+    async fn send_thread_reply(&self, channel: &str, thread: &str, message: &str) -> SendResult {
+        let (channel, _) = channel.split_once(':').unwrap_or((channel, ""));
+        self.send(&format!("{}:{}", channel, thread), message).await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            emote: true,
+            topic: true,
+            join: true,
+            part: true,
+            react: true,
+            threads: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+/// open a Socket Mode connection
+async fn open(api: &Api) -> Result<Socket> {
+    let resp = api.app("apps.connections.open", &json!({})).await?;
+    let url = resp["url"]
+        .as_str()
+        .ok_or_else(|| SlackError::Api("missing url".to_string()))?;
+    ws_connect(url).await
+}
+
+async fn user_name(api: &Api, users: &Mutex<Users>, id: &str) -> String {
+    if let Some(name) = users.lock().unwrap().names.get(id) {
+        return name.clone();
+    }
+
+    match api.bot("users.info", &json!({ "user": id })).await {
+        Ok(resp) => {
+            let name = resp["user"]["name"].as_str().unwrap_or(id).to_string();
+            users.lock().unwrap().insert(id, &name);
+            name
+        }
+        Err(e) => {
+            log::error!("users.info fail: {e}");
+            id.to_string()
+        }
+    }
+}
+
+async fn to_message(
+    api: &Api,
+    users: &Mutex<Users>,
+    user_id: &str,
+    name: &str,
+    event: &Value,
+) -> Option<Message> {
+    if event["type"].as_str() != Some("message") || event["subtype"].is_string() {
+        return None;
+    }
+    let user = event["user"].as_str()?;
+    if user == user_id || event["bot_id"].is_string() {
+        return None;
+    }
+
+    let mut channel = event["channel"].as_str()?.to_string();
+    if let Some(ts) = event["thread_ts"].as_str() {
+        channel = format!("{}:{}", channel, ts);
+    }
+    let text = event["text"].as_str().unwrap_or_default();
+    // names are looked up ahead, decode resolves mentions synchronously
+    let mut names = HashMap::new();
+    for id in mentions(text) {
+        if id != user_id && !names.contains_key(id) {
+            names.insert(id, user_name(api, users, id).await);
+        }
+    }
+    let message = decode(text, |id| {
+        if id == user_id {
+            name.to_string()
+        } else {
+            format!("@{}", names.get(id).map(String::as_str).unwrap_or(id))
+        }
+    });
+
+    let nick = user_name(api, users, user).await;
+    let mut msg = Message::new(&channel, &nick, &message, MessageKind::Text);
+    let ts = event["ts"].as_str();
+    if let Some(secs) = ts.and_then(|ts| ts.parse::<f64>().ok()) {
+        msg.time = UNIX_EPOCH + Duration::from_secs_f64(secs);
+    }
+    msg.id = ts.map(String::from);
+    msg.direct = event["channel_type"] == "im";
+    msg.server = Some(ServerType::Slack);
+    msg.account = Some(user.to_string());
+    msg.raw = Some(event.to_string());
+    Some(msg)
+}
+
+/// user ids mentioned as `<@U123>`
+fn mentions(text: &str) -> impl Iterator<Item = &str> {
+    text.split("<@")
+        .skip(1)
+        .filter_map(|rest| rest.split(['>', '|']).next())
+}
+
+/// translate slack markup into plain text
+///
+/// `<@U123>` is resolved by `mention`, `<#C123|general>` -> `#general`,
+/// `<https://example.com|label>` -> `https://example.com`
+fn decode<F>(text: &str, mention: F) -> String
+where
+    F: Fn(&str) -> String,
+{
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let inner = &rest[(start + 1)..(start + end)];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        if let Some(id) = target.strip_prefix('@') {
+            out.push_str(&mention(id));
+        } else if let Some(id) = target.strip_prefix('#') {
+            out.push('#');
+            out.push_str(label.unwrap_or(id));
+        } else if let Some(special) = target.strip_prefix('!') {
+            out.push('@');
+            out.push_str(label.unwrap_or(special));
+        } else {
+            out.push_str(target);
+        }
+        rest = &rest[(start + end + 1)..];
+    }
+    out.push_str(rest);
+
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// escape text for slack, a leading known `nick:` becomes a mention
+fn encode(users: &Users, message: &str) -> String {
+    let escaped = message
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    if let Some((nick, rest)) = escaped.split_once(": ") {
+        if let Some(id) = users.ids.get(nick) {
+            return format!("<@{}>: {}", id, rest);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::{config::SlackConfig, server::stub};
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mention = |id: &str| match id {
+            "UBOT" => "hongbot".to_string(),
+            _ => format!("@{}", id.to_lowercase()),
+        };
+        assert_eq!(decode("<@UBOT> ping", mention), "hongbot ping");
+        assert_eq!(decode("hi <@UALICE>!", mention), "hi @ualice!");
+        assert_eq!(decode("see <#C123|general>", mention), "see #general");
+        assert_eq!(
+            decode("<https://example.com|example> &lt;3", mention),
+            "https://example.com <3"
+        );
+        assert_eq!(decode("<!here> deploy", mention), "@here deploy");
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut users = Users::default();
+        users.insert("U1", "alice");
+        assert_eq!(encode(&users, "alice: pong"), "<@U1>: pong");
+        assert_eq!(encode(&users, "bob: 1 < 2"), "bob: 1 &lt; 2");
+    }
+
+    #[tokio::test]
+    async fn test_socket_mode() {
+        // local stand-in for the Slack Web API and Socket Mode websocket
+        let (listener, ws_addr) = stub::listen();
+        let (posted_tx, mut posted_rx) = unbounded_channel::<Value>();
+        let http_addr = stub::http(move |req| match req.url.as_str() {
+            "/auth.test" => json!({ "ok": true, "user_id": "UBOT" }),
+            "/apps.connections.open" => json!({ "ok": true, "url": format!("ws://{}/", ws_addr) }),
+            "/users.info" => json!({ "ok": true, "user": { "name": "alice" } }),
+            "/chat.postMessage" => {
+                posted_tx.send(req.body).unwrap();
+                json!({ "ok": true })
+            }
+            _ => json!({ "ok": false, "error": "unknown_method" }),
+        });
+
+        let (ack_tx, mut ack_rx) = unbounded_channel::<Value>();
+        thread::spawn(move || {
+            let mut ws = stub::accept(&listener);
+            stub::write_json(&mut ws, &json!({ "type": "hello" }));
+            let envelope = json!({
+                "envelope_id": "e1",
+                "type": "events_api",
+                "payload": {
+                    "event": {
+                        "type": "message",
+                        "channel": "C1",
+                        "user": "UALICE",
+                        "text": "<@UBOT> ping",
+                        "ts": "2.0",
+                        "thread_ts": "1.0",
+                    }
+                }
+            });
+            stub::write_json(&mut ws, &envelope);
+            while let Ok(msg) = ws.read_message() {
+                if let WsMessage::Text(text) = msg {
+                    ack_tx.send(serde_json::from_str(&text).unwrap()).unwrap();
+                }
+            }
+        });
+
+        let slack = Slack::new(
+            "hongbot".to_string(),
+            SlackConfig {
+                app_token: "xapp-test".to_string(),
+                bot_token: "xoxb-test".to_string(),
+                api_url: Some(format!("http://{}", http_addr)),
+            },
+        );
+        let (tx, mut rx) = unbounded_channel();
+        let handle = slack.connect(tx).await.unwrap();
+
+        let msg = stub::recv_message(&mut rx, "C1:1.0", "alice", "hongbot ping").await;
+        let ack = stub::recv(&mut ack_rx).await;
+        assert_eq!(ack["envelope_id"], "e1");
+
+        slack.send(&msg.channel, "alice: pong").await.unwrap();
+        let posted = stub::recv(&mut posted_rx).await;
+        assert_eq!(posted["channel"], "C1");
+        assert_eq!(posted["thread_ts"], "1.0");
+        assert_eq!(posted["text"], "<@UALICE>: pong");
+
+        slack.disconnect().await;
+        stub::join(handle).await;
+    }
+
+    #[tokio::test]
+    async fn test_dropped_session() {
+        // sockets closed before hello, e.g. an overloaded server
+        let (listener, ws_addr) = stub::listen();
+        let http_addr = stub::http(move |req| match req.url.as_str() {
+            "/auth.test" => json!({ "ok": true, "user_id": "UBOT" }),
+            _ => json!({ "ok": true, "url": format!("ws://{}/", ws_addr) }),
+        });
+        let (conn_tx, mut conn_rx) = unbounded_channel::<()>();
+        thread::spawn(move || loop {
+            let mut ws = stub::accept(&listener);
+            conn_tx.send(()).unwrap();
+            ws.close(None).unwrap();
+            while ws.read_message().is_ok() {}
+        });
+
+        let slack = Slack::new(
+            "hongbot".to_string(),
+            SlackConfig {
+                app_token: "xapp-test".to_string(),
+                bot_token: "xoxb-test".to_string(),
+                api_url: Some(format!("http://{}", http_addr)),
+            },
+        );
+        let (tx, _rx) = unbounded_channel();
+        let handle = slack.connect(tx).await.unwrap();
+
+        stub::recv(&mut conn_rx).await;
+        // the next attempt waits
+        let again = tokio::time::timeout(Duration::from_millis(500), conn_rx.recv()).await;
+        assert!(again.is_err());
+
+        // without waiting out the delay
+        slack.disconnect().await;
+        tokio::time::timeout(Duration::from_millis(500), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
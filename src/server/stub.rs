@@ -0,0 +1,150 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle, time::timeout};
+use tungstenite::{Message as WsMessage, WebSocket};
+
+use crate::bot::Message;
+
+/// how long a test waits on the adapter
+const WAIT: Duration = Duration::from_secs(5);
+
+/// http request seen by a stand-in
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub auth: Option<String>,
+    pub body: Value,
+}
+
+/// status and json body answered by a stand-in, plain json is a 200
+pub struct Reply(pub u16, pub Value);
+
+impl From<Value> for Reply {
+    fn from(body: Value) -> Self {
+        Reply(200, body)
+    }
+}
+
+/// serve http on a local port, `handler` answers every request
+pub fn http<F, R>(mut handler: F) -> SocketAddr
+where
+    F: FnMut(Request) -> R + Send + 'static,
+    R: Into<Reply>,
+{
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let addr = server.server_addr().to_ip().unwrap();
+    thread::spawn(move || {
+        for mut req in server.incoming_requests() {
+            let mut body = String::new();
+            req.as_reader().read_to_string(&mut body).unwrap();
+            let auth = req
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            let Reply(code, resp) = handler(Request {
+                method: req.method().to_string(),
+                url: req.url().to_string(),
+                auth,
+                body: serde_json::from_str(&body).unwrap_or(Value::Null),
+            })
+            .into();
+            let resp = tiny_http::Response::from_string(resp.to_string()).with_status_code(code);
+            req.respond(resp).unwrap();
+        }
+    });
+    addr
+}
+
+/// answer one raw http request, returns request line and body
+pub fn respond(stream: TcpStream, resp: &Value) -> (String, Value) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                length = v.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+
+    let resp = resp.to_string();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        resp.len(),
+        resp
+    )
+    .unwrap();
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (request_line.trim().to_string(), body)
+}
+
+/// bind a local port for a scripted server
+pub fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+/// accept the next websocket client
+pub fn accept(listener: &TcpListener) -> WebSocket<TcpStream> {
+    let (stream, _) = listener.accept().unwrap();
+    tungstenite::accept(stream).unwrap()
+}
+
+/// write a json text frame
+pub fn write_json(ws: &mut WebSocket<TcpStream>, payload: &Value) {
+    ws.write_message(WsMessage::Text(payload.to_string()))
+        .unwrap();
+}
+
+/// read the next json text frame
+pub fn read_json(ws: &mut WebSocket<TcpStream>) -> Value {
+    loop {
+        if let WsMessage::Text(text) = ws.read_message().unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// next value from the adapter or a stand-in, panics if none arrives in time
+pub async fn recv<T>(rx: &mut UnboundedReceiver<T>) -> T {
+    timeout(WAIT, rx.recv()).await.unwrap().unwrap()
+}
+
+/// next message passed to the bot, checked against the expected fields
+pub async fn recv_message(
+    rx: &mut UnboundedReceiver<Message>,
+    channel: &str,
+    nick: &str,
+    message: &str,
+) -> Message {
+    let msg = recv(rx).await;
+    assert_eq!(msg.channel, channel);
+    assert_eq!(msg.nick, nick);
+    assert_eq!(msg.message, message);
+    msg
+}
+
+/// wait for the adapter's background task to stop
+pub async fn join(handle: JoinHandle<()>) {
+    timeout(WAIT, handle).await.unwrap().unwrap();
+}
@@ -0,0 +1,414 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::TelegramConfig,
+};
+
+use super::{
+    net::{request, send_error, stopped, Backoff},
+    SendError, SendResult, Server,
+};
+
+const API_URL: &str = "https://api.telegram.org";
+// long-polling timeout of getUpdates in seconds
+const POLL_TIMEOUT: u64 = 30;
+// characters of a text message
+const MAX_MESSAGE: usize = 4096;
+
+/// Telegram adapter using the Bot API.
+///
+/// Chat ids are used as channels, `/command@bot` and `@bot` mentions are
+/// rewritten to address the bot by its configured name.
+#[derive(Debug)]
+pub struct Telegram {
+    name: String,
+    config: TelegramConfig,
+    accepted: watch::Sender<bool>,
+}
+
+#[derive(Debug, Error)]
+enum TelegramError {
+    #[error("telegram api error: {code} {description}")]
+    Api {
+        code: i64,
+        description: String,
+        /// seconds, when rate limited
+        retry_after: Option<u64>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Api {
+    url: String,
+}
+
+impl Api {
+    async fn call(&self, method: &str, params: &Value) -> Result<Value> {
+        let url = format!("{}/{}", self.url, method);
+        let (_, resp) = request("POST", &url, &[], Some(params))
+            .await
+            .map_err(|e| match e.downcast::<reqwest::Error>() {
+                // the url holds the token, keep it out of the logs
+                Ok(e) => e.without_url().into(),
+                Err(e) => e,
+            })?;
+        if resp["ok"].as_bool() != Some(true) {
+            let description = resp["description"].as_str().unwrap_or("unknown");
+            return Err(TelegramError::Api {
+                code: resp["error_code"].as_i64().unwrap_or_default(),
+                description: description.to_string(),
+                retry_after: resp["parameters"]["retry_after"].as_u64(),
+            }
+            .into());
+        }
+        Ok(resp["result"].clone())
+    }
+}
+
+impl Telegram {
+    pub fn new(name: String, config: TelegramConfig) -> Self {
+        Telegram {
+            name,
+            config,
+            accepted: watch::channel(false).0,
+        }
+    }
+
+    fn api(&self) -> Api {
+        let base = self.config.api_url.as_deref().unwrap_or(API_URL);
+        Api {
+            url: format!("{}/bot{}", base.trim_end_matches('/'), self.config.token),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Telegram {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let api = self.api();
+        let me = api.call("getMe", &json!({})).await?;
+        let username = me["username"].as_str().unwrap_or_default().to_string();
+        log::trace!("logged in as @{}", username);
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let name = self.name.clone();
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            let mut offset = 0;
+            while *accepted.borrow() {
+                let params = json!({
+                    "offset": offset,
+                    "timeout": POLL_TIMEOUT,
+                    "allowed_updates": ["message"],
+                });
+                let updates = tokio::select! {
+                    _ = stopped(&mut accepted) => break,
+                    updates = api.call("getUpdates", &params) => updates,
+                };
+                let updates = match updates {
+                    Ok(updates) => updates,
+                    Err(e) => {
+                        log::error!("getUpdates fail: {e}");
+                        backoff.wait(&mut accepted).await;
+                        continue;
+                    }
+                };
+                backoff.reset();
+
+                for update in updates.as_array().into_iter().flatten() {
+                    if let Some(id) = update["update_id"].as_i64() {
+                        offset = offset.max(id + 1);
+                    }
+                    if let Some(msg) = to_message(&name, &username, &update["message"]) {
+                        if tx.send(msg).is_err() {
+                            log::error!("receiver dropped, stop reading");
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        if message.chars().count() > MAX_MESSAGE {
+            return Err(SendError::TooLong(MAX_MESSAGE));
+        }
+        self.api()
+            .call(
+                "sendMessage",
+                &json!({ "chat_id": channel, "text": message }),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                send_error(e, |err: &TelegramError| match err {
+                    TelegramError::Api {
+                        code: 429,
+                        retry_after,
+                        ..
+                    } => Some(SendError::RateLimited(retry_after.map(Duration::from_secs))),
+                    // bot was blocked or kicked
+                    TelegramError::Api { code: 403, .. } => {
+                        Some(SendError::UnknownTarget(channel.to_string()))
+                    }
+                    TelegramError::Api {
+                        code: 400,
+                        description,
+                        ..
+                    } if description.contains("chat not found") => {
+                        Some(SendError::UnknownTarget(channel.to_string()))
+                    }
+                    _ => None,
+                })
+            })
+    }
+}
+
+fn to_message(name: &str, username: &str, message: &Value) -> Option<Message> {
+    let text = message["text"].as_str()?;
+    let chat = message["chat"]["id"].as_i64()?;
+    let from = &message["from"];
+    if from["is_bot"].as_bool() == Some(true) {
+        return None;
+    }
+    let nick = from["username"]
+        .as_str()
+        .or_else(|| from["first_name"].as_str())?;
+
+    let mut msg = Message::new(
+        &chat.to_string(),
+        nick,
+        &address(name, username, text),
+        MessageKind::Text,
+    );
+    if let Some(date) = message["date"].as_u64() {
+        msg.time = UNIX_EPOCH + Duration::from_secs(date);
+    }
+    msg.id = message["message_id"].as_i64().map(|id| id.to_string());
+    msg.direct = message["chat"]["type"] == "private";
+    msg.server = Some(ServerType::Telegram);
+    msg.account = from["id"].as_i64().map(|id| id.to_string());
+    msg.raw = Some(message.to_string());
+    Some(msg)
+}
+
+/// rewrite telegram addressing into `<name> ...`
+///
+/// `/ping@hongbot_bot 5` and `/ping 5` -> `hongbot ping 5`,
+/// `@hongbot_bot ping` -> `hongbot ping`
+fn address(name: &str, username: &str, text: &str) -> String {
+    let mention = format!("@{}", username);
+    if let Some(command) = text.strip_prefix('/') {
+        let (head, args) = match command.split_once(' ') {
+            Some((head, args)) => (head, Some(args)),
+            None => (command, None),
+        };
+        let cmd = match head.split_once('@') {
+            Some((cmd, to)) if to.eq_ignore_ascii_case(username) => cmd,
+            Some(_) => return text.to_string(),
+            None => head,
+        };
+        return match args {
+            Some(args) => format!("{} {} {}", name, cmd, args),
+            None => format!("{} {}", name, cmd),
+        };
+    }
+    if let Some(rest) = text.strip_prefix(&mention) {
+        return format!("{}{}", name, rest);
+    }
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::server::stub;
+
+    use super::*;
+
+    #[test]
+    fn test_address() {
+        let addr = |text| address("hongbot", "hongbot_bot", text);
+        assert_eq!(addr("/ping@hongbot_bot"), "hongbot ping");
+        assert_eq!(addr("/ping@HongBot_Bot 5"), "hongbot ping 5");
+        assert_eq!(addr("/ping"), "hongbot ping");
+        assert_eq!(addr("/ping@otherbot"), "/ping@otherbot");
+        assert_eq!(addr("@hongbot_bot: ping"), "hongbot: ping");
+        assert_eq!(addr("hello"), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_long_polling() {
+        // local stub of the Bot API
+        let (req_tx, mut req_rx) = unbounded_channel::<(String, Value)>();
+        let addr = stub::http(move |req| {
+            let result = match req.url.as_str() {
+                "/botsecret/getMe" => json!({ "id": 1, "username": "hongbot_bot" }),
+                "/botsecret/getUpdates" if req.body["offset"] == 0 => json!([{
+                    "update_id": 10,
+                    "message": {
+                        "message_id": 1,
+                        "from": { "id": 2, "is_bot": false, "username": "alice" },
+                        "chat": { "id": -100, "type": "group", "title": "ops" },
+                        "text": "/ping@hongbot_bot",
+                    },
+                }]),
+                "/botsecret/getUpdates" => {
+                    req_tx.send((req.url, req.body)).unwrap();
+                    thread::sleep(Duration::from_millis(100));
+                    json!([])
+                }
+                _ => {
+                    req_tx.send((req.url, req.body)).unwrap();
+                    json!({ "message_id": 2 })
+                }
+            };
+            json!({ "ok": true, "result": result })
+        });
+
+        let telegram = Telegram::new(
+            "hongbot".to_string(),
+            TelegramConfig {
+                token: "secret".to_string(),
+                api_url: Some(format!("http://{}", addr)),
+            },
+        );
+        let (tx, mut rx) = unbounded_channel();
+        let handle = telegram.connect(tx).await.unwrap();
+
+        let msg = stub::recv_message(&mut rx, "-100", "alice", "hongbot ping").await;
+
+        // next poll acknowledges the update
+        let (url, body) = stub::recv(&mut req_rx).await;
+        assert_eq!(url, "/botsecret/getUpdates");
+        assert_eq!(body["offset"], 11);
+
+        telegram.send(&msg.channel, "alice: pong").await.unwrap();
+        let (url, body) = loop {
+            let (url, body) = stub::recv(&mut req_rx).await;
+            if url != "/botsecret/getUpdates" {
+                break (url, body);
+            }
+        };
+        assert_eq!(url, "/botsecret/sendMessage");
+        assert_eq!(body["chat_id"], "-100");
+        assert_eq!(body["text"], "alice: pong");
+
+        telegram.disconnect().await;
+        stub::join(handle).await;
+    }
+
+    #[tokio::test]
+    async fn test_send_error() {
+        let addr = stub::http(|req| match req.body["chat_id"].as_str() {
+            Some("-100") => json!({
+                "ok": false,
+                "error_code": 429,
+                "description": "Too Many Requests: retry after 7",
+                "parameters": { "retry_after": 7 },
+            }),
+            _ => json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: chat not found",
+            }),
+        });
+
+        let telegram = Telegram::new(
+            "hongbot".to_string(),
+            TelegramConfig {
+                token: "secret".to_string(),
+                api_url: Some(format!("http://{}", addr)),
+            },
+        );
+        assert!(matches!(
+            telegram.send("-100", "pong").await,
+            Err(SendError::RateLimited(Some(d))) if d == Duration::from_secs(7)
+        ));
+        assert!(matches!(
+            telegram.send("-200", "pong").await,
+            Err(SendError::UnknownTarget(chat)) if chat == "-200"
+        ));
+        assert!(matches!(
+            telegram.send("-100", &"a".repeat(MAX_MESSAGE + 1)).await,
+            Err(SendError::TooLong(MAX_MESSAGE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_token_hidden() {
+        // nothing listens once the listener is dropped
+        let (_, addr) = stub::listen();
+        let telegram = Telegram::new(
+            "hongbot".to_string(),
+            TelegramConfig {
+                token: "secret".to_string(),
+                api_url: Some(format!("http://{}", addr)),
+            },
+        );
+
+        let (tx, _rx) = unbounded_channel();
+        let e = telegram.connect(tx).await.unwrap_err();
+        assert!(!format!("{e:#} {e:?}").contains("secret"));
+        let e = telegram.send("-100", "pong").await.unwrap_err();
+        assert!(matches!(e, SendError::Disconnected));
+        assert!(!format!("{e} {e:?}").contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_fail() {
+        let (poll_tx, mut poll_rx) = unbounded_channel::<()>();
+        let addr = stub::http(move |req| {
+            if req.url == "/botsecret/getMe" {
+                return json!({ "ok": true, "result": { "id": 1, "username": "hongbot_bot" } });
+            }
+            poll_tx.send(()).unwrap();
+            json!({ "ok": false, "error_code": 502, "description": "Bad Gateway" })
+        });
+
+        let telegram = Telegram::new(
+            "hongbot".to_string(),
+            TelegramConfig {
+                token: "secret".to_string(),
+                api_url: Some(format!("http://{}", addr)),
+            },
+        );
+        let (tx, _rx) = unbounded_channel();
+        let handle = telegram.connect(tx).await.unwrap();
+
+        stub::recv(&mut poll_rx).await;
+        // the next attempt waits
+        let again = tokio::time::timeout(Duration::from_millis(500), poll_rx.recv()).await;
+        assert!(again.is_err());
+
+        // without waiting out the delay
+        telegram.disconnect().await;
+        tokio::time::timeout(Duration::from_millis(500), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::bot::{Bot, Message, MessageKind};
+
+use super::{Capabilities, SendError, SendResult, Server};
+
+/// In-memory server for testing handlers, it records what the bot sends.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use hongbot_rs::{
+///     action::Action,
+///     bot::Bot,
+///     server::test::{Sent, TestServer},
+/// };
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let server = TestServer::new();
+/// let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+/// bot.respond("ping", Action::ping).unwrap();
+///
+/// server.inject(&mut bot, "#ops", "alice", "hongbot: ping").await;
+/// assert_eq!(
+///     server.drain(&bot).await,
+///     vec![Sent::Reply {
+///         channel: "#ops".to_string(),
+///         nick: "alice".to_string(),
+///         message: "pong".to_string(),
+///     }]
+/// );
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TestServer {
+    sent: Arc<Mutex<Vec<Sent>>>,
+    monitored: Arc<Mutex<Vec<String>>>,
+    fail: Arc<Mutex<Option<SendError>>>,
+}
+
+/// output of the bot, one variant per `Server` method
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sent {
+    Send {
+        channel: String,
+        message: String,
+    },
+    Reply {
+        channel: String,
+        nick: String,
+        message: String,
+    },
+    Emote {
+        channel: String,
+        action: String,
+    },
+    Notice {
+        channel: String,
+        message: String,
+    },
+    Topic {
+        channel: String,
+        topic: String,
+    },
+    Join {
+        channel: String,
+    },
+    Part {
+        channel: String,
+    },
+    React {
+        channel: String,
+        id: String,
+        reaction: String,
+    },
+    ThreadReply {
+        channel: String,
+        thread: String,
+        message: String,
+    },
+}
+
+impl Sent {
+    pub fn channel(&self) -> &str {
+        match self {
+            Sent::Send { channel, .. }
+            | Sent::Reply { channel, .. }
+            | Sent::Emote { channel, .. }
+            | Sent::Notice { channel, .. }
+            | Sent::Topic { channel, .. }
+            | Sent::Join { channel }
+            | Sent::Part { channel }
+            | Sent::React { channel, .. }
+            | Sent::ThreadReply { channel, .. } => channel,
+        }
+    }
+}
+
+/// `Sent::Send`
+impl From<(&str, &str)> for Sent {
+    fn from((channel, message): (&str, &str)) -> Self {
+        Sent::Send {
+            channel: channel.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// delivers a message to the bot as if `nick` said it on `channel`,
+    /// returns false once the bot shut down
+    pub async fn inject(&self, bot: &mut Bot, channel: &str, nick: &str, message: &str) -> bool {
+        bot.receive(Message::new(channel, nick, message, MessageKind::Text))
+            .await
+    }
+
+    /// waits for background work of the bot, see `Bot::spawn`, and takes
+    /// everything sent so far
+    pub async fn drain(&self, bot: &Bot) -> Vec<Sent> {
+        bot.wait().await;
+        self.sent.lock().unwrap().drain(..).collect()
+    }
+
+    fn record(&self, sent: Sent) -> SendResult {
+        if let Some(err) = self.fail.lock().unwrap().take() {
+            return Err(err);
+        }
+        self.sent.lock().unwrap().push(sent);
+        Ok(())
+    }
+
+    /// makes the next output fail with `err`
+    pub fn fail_next(&self, err: SendError) {
+        *self.fail.lock().unwrap() = Some(err);
+    }
+
+    /// nicks currently watched with `Bot::monitor`
+    pub fn monitored(&self) -> Vec<String> {
+        self.monitored.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Server for TestServer {
+    async fn connect(&self, _tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        // messages are delivered with inject
+        Ok(tokio::spawn(async {}))
+    }
+
+    async fn disconnect(&self) {}
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        self.record((channel, message).into())
+    }
+
+    async fn reply(&self, channel: &str, nick: &str, message: &str) -> SendResult {
+        self.record(Sent::Reply {
+            channel: channel.to_string(),
+            nick: nick.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    async fn emote(&self, channel: &str, action: &str) -> SendResult {
+        self.record(Sent::Emote {
+            channel: channel.to_string(),
+            action: action.to_string(),
+        })
+    }
+
+    async fn notice(&self, channel: &str, message: &str) -> SendResult {
+        self.record(Sent::Notice {
+            channel: channel.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    async fn topic(&self, channel: &str, topic: &str) -> SendResult {
+        self.record(Sent::Topic {
+            channel: channel.to_string(),
+            topic: topic.to_string(),
+        })
+    }
+
+    async fn join(&self, channel: &str) -> SendResult {
+        self.record(Sent::Join {
+            channel: channel.to_string(),
+        })
+    }
+
+    async fn part(&self, channel: &str) -> SendResult {
+        self.record(Sent::Part {
+            channel: channel.to_string(),
+        })
+    }
+
+    async fn react(&self, channel: &str, id: &str, reaction: &str) -> SendResult {
+        self.record(Sent::React {
+            channel: channel.to_string(),
+            id: id.to_string(),
+            reaction: reaction.to_string(),
+        })
+    }
+
+    async fn send_thread_reply(&self, channel: &str, thread: &str, message: &str) -> SendResult {
+        self.record(Sent::ThreadReply {
+            channel: channel.to_string(),
+            thread: thread.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            emote: true,
+            notice: true,
+            topic: true,
+            join: true,
+            part: true,
+            react: true,
+            threads: true,
+        }
+    }
+
+    async fn monitor(&self, nicks: &[String]) {
+        let mut monitored = self.monitored.lock().unwrap();
+        for nick in nicks {
+            if !monitored.contains(nick) {
+                monitored.push(nick.clone());
+            }
+        }
+    }
+
+    async fn unmonitor(&self, nicks: &[String]) {
+        self.monitored
+            .lock()
+            .unwrap()
+            .retain(|n| !nicks.contains(n));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::Action;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_background_work() {
+        let server = TestServer::new();
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.respond("ping", Action::ping).unwrap();
+        bot.respond("later", Action::ping_delayed).unwrap();
+
+        assert!(
+            server
+                .inject(&mut bot, "#ops", "alice", "hongbot: later")
+                .await
+        );
+        assert!(
+            server
+                .inject(&mut bot, "#ops", "alice", "hongbot: ping")
+                .await
+        );
+        assert!(server.inject(&mut bot, "#ops", "alice", "ping").await);
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![
+                Sent::Reply {
+                    channel: "#ops".to_string(),
+                    nick: "alice".to_string(),
+                    message: "pong".to_string(),
+                },
+                Sent::from(("#ops", "pong"))
+            ]
+        );
+        assert!(server.drain(&bot).await.is_empty());
+
+        bot.monitor("bob").await;
+        assert_eq!(server.monitored(), vec!["bob".to_string()]);
+
+        assert!(
+            !server
+                .inject(&mut bot, "#ops", "alice", "hongbot: shutdown")
+                .await
+        );
+        assert_eq!(server.drain(&bot).await, vec![Sent::from(("#ops", "bye"))]);
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let server = TestServer::new();
+        let bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        assert!(bot.capabilities().emote);
+
+        bot.emote("#ops", "waves").await.unwrap();
+        bot.topic("#ops", "release day").await.unwrap();
+        bot.send_thread_reply("#ops", "1234", "done").await.unwrap();
+        let sent = server.drain(&bot).await;
+        assert_eq!(
+            sent,
+            vec![
+                Sent::Emote {
+                    channel: "#ops".to_string(),
+                    action: "waves".to_string(),
+                },
+                Sent::Topic {
+                    channel: "#ops".to_string(),
+                    topic: "release day".to_string(),
+                },
+                Sent::ThreadReply {
+                    channel: "#ops".to_string(),
+                    thread: "1234".to_string(),
+                    message: "done".to_string(),
+                },
+            ]
+        );
+        assert!(sent.iter().all(|s| s.channel() == "#ops"));
+    }
+
+    #[tokio::test]
+    async fn test_fail_next() {
+        let server = TestServer::new();
+        let bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        server.fail_next(SendError::UnknownTarget("#nowhere".to_string()));
+        assert!(matches!(
+            bot.send("#nowhere", "hello").await,
+            Err(SendError::UnknownTarget(target)) if target == "#nowhere"
+        ));
+        bot.send("#ops", "hello").await.unwrap();
+        assert_eq!(
+            server.drain(&bot).await,
+            vec![Sent::from(("#ops", "hello"))]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::WebhookConfig,
+    http::{error_resp, Method, Request, Response, Route},
+};
+
+use super::{
+    net::{request, send_error},
+    SendError, SendResult, Server,
+};
+
+const WEBHOOK_PATH: &str = "/webhook";
+// bytes of an incoming request body
+const MAX_BODY: usize = 1 << 20;
+
+/// Webhook adapter, incoming messages are POSTed to the builtin http server
+/// and bot output is POSTed as json to the configured url.
+///
+/// incoming: `{"channel": "#ops", "nick": "alice", "message": "hongbot: ping"}`
+/// outgoing: `{"channel": "#ops", "message": "alice: pong"}`
+#[derive(Debug)]
+pub struct Webhook {
+    config: WebhookConfig,
+    tx: Arc<Mutex<Option<UnboundedSender<Message>>>>,
+}
+
+#[derive(Debug, Error)]
+enum WebhookError {
+    #[error("webhook responded with {0}")]
+    Status(u16),
+}
+
+#[derive(Debug, Deserialize)]
+struct Incoming {
+    channel: String,
+    nick: String,
+    message: String,
+}
+
+impl Webhook {
+    pub fn new(config: WebhookConfig) -> Self {
+        Webhook {
+            config,
+            tx: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Webhook {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        *self.tx.lock().unwrap() = Some(tx);
+        // requests are handled by the http server, nothing to run here
+        Ok(tokio::spawn(async {}))
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.tx.lock().unwrap().take();
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let mut headers = Vec::new();
+        if let Some(token) = &self.config.token {
+            headers.push(format!("Authorization: Bearer {}", token));
+        }
+        let body = json!({ "channel": channel, "message": message });
+        let (code, _) = request("POST", &self.config.url, &headers, Some(&body))
+            .await
+            .map_err(|e| send_error(e, |_: &WebhookError| None))?;
+        match code {
+            200..=299 => Ok(()),
+            429 => Err(SendError::RateLimited(None)),
+            _ => Err(SendError::Other(WebhookError::Status(code).into())),
+        }
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        let tx = self.tx.clone();
+        let token = self.config.token.clone();
+        let handler = move |req: Request| {
+            let tx = tx.clone();
+            let token = token.clone();
+            Box::pin(async move {
+                if let Some(token) = &token {
+                    let authorized = req
+                        .headers()
+                        .get("Authorization")
+                        .is_some_and(|v| v.as_bytes() == format!("Bearer {}", token).as_bytes());
+                    if !authorized {
+                        return error_resp(401);
+                    }
+                }
+
+                let body = match axum::body::to_bytes(req.into_body(), MAX_BODY).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        log::error!("webhook body read fail: {e}");
+                        return error_resp(400);
+                    }
+                };
+                let incoming: Incoming = match serde_json::from_slice(&body) {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        log::error!("unexpected webhook body: {e}");
+                        return error_resp(400);
+                    }
+                };
+                match tx.lock().unwrap().as_ref() {
+                    Some(tx) => {
+                        let mut msg = Message::new(
+                            &incoming.channel,
+                            &incoming.nick,
+                            &incoming.message,
+                            MessageKind::Text,
+                        );
+                        msg.server = Some(ServerType::Webhook);
+                        msg.raw = Some(String::from_utf8_lossy(&body).to_string());
+                        match tx.send(msg) {
+                            Ok(()) => Response::new("OK".into()),
+                            Err(_) => {
+                                log::error!("receiver dropped");
+                                error_resp(503)
+                            }
+                        }
+                    }
+                    None => error_resp(503),
+                }
+            }) as BoxFuture<'static, Response>
+        };
+
+        vec![Route {
+            method: Method::POST,
+            path: self
+                .config
+                .path
+                .clone()
+                .unwrap_or_else(|| WEBHOOK_PATH.to_string()),
+            handler: Box::new(handler),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::{http::serve, server::stub};
+
+    use super::*;
+
+    #[test]
+    fn test_path() {
+        let config = |path: &str| WebhookConfig {
+            path: Some(path.to_string()),
+            url: "http://127.0.0.1/hook".to_string(),
+            token: None,
+        };
+        assert!(config("/hooks/chat").validate().is_ok());
+        for path in ["webhook", "", "/", "/:id", "/hooks/*rest"] {
+            let e = config(path).validate().unwrap_err();
+            assert!(e.to_string().contains("webhook path"), "{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        // receiver of bot output
+        let (posted_tx, mut posted_rx) = unbounded_channel::<(Option<String>, Value)>();
+        let out_addr = stub::http(move |req| {
+            posted_tx.send((req.auth, req.body)).unwrap();
+            json!({})
+        });
+
+        let webhook = Webhook::new(WebhookConfig {
+            path: None,
+            url: format!("http://{}/hook", out_addr),
+            token: Some("secret".to_string()),
+        });
+        let http_server = serve("127.0.0.1:0", webhook.routes()).await.unwrap();
+        let url = format!("http://{}/webhook", http_server.addr);
+        let (tx, mut rx) = unbounded_channel();
+        webhook.connect(tx).await.unwrap().await.unwrap();
+
+        let body = json!({ "channel": "#ops", "nick": "alice", "message": "hongbot: ping" });
+        let (code, _) = request("POST", &url, &[], Some(&body)).await.unwrap();
+        assert_eq!(code, 401);
+
+        let auth = ["Authorization: Bearer secret".to_string()];
+        let (code, _) = request("POST", &url, &auth, Some(&json!({ "nick": "alice" })))
+            .await
+            .unwrap();
+        assert_eq!(code, 400);
+
+        let (code, _) = request("POST", &url, &auth, Some(&body)).await.unwrap();
+        assert_eq!(code, 200);
+        stub::recv_message(&mut rx, "#ops", "alice", "hongbot: ping").await;
+
+        webhook.send("#ops", "alice: pong").await.unwrap();
+        let (auth, body) = stub::recv(&mut posted_rx).await;
+        assert_eq!(auth.as_deref(), Some("Bearer secret"));
+        assert_eq!(body, json!({ "channel": "#ops", "message": "alice: pong" }));
+
+        http_server.shutdown().await;
+    }
+}
@@ -0,0 +1,845 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use quick_xml::{events::BytesStart, events::Event, Reader};
+use thiserror::Error;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    bot::{Message, MessageKind, ServerType},
+    config::XmppConfig,
+};
+
+use super::{
+    net::{stopped, Backoff},
+    SendError, SendResult, Server,
+};
+
+const DEFAULT_PORT: u16 = 5222;
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_MUC: &str = "http://jabber.org/protocol/muc";
+const NS_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+
+/// XMPP adapter, joins multi-user chat rooms and answers direct chats.
+///
+/// Room jids (`room@conference.example.org`) are channels with occupant
+/// nicks, a direct chat uses the bare jid of the peer as channel and a
+/// private message from an occupant its full jid, `room@muc/alice`.
+pub struct Xmpp {
+    name: String,
+    config: XmppConfig,
+    accepted: watch::Sender<bool>,
+    writer: Arc<Mutex<Option<Writer>>>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Stream for T {}
+
+/// outgoing half of the connection, shared by the bot and the reader task
+type Writer = Arc<tokio::sync::Mutex<WriteHalf<Box<dyn Stream>>>>;
+
+#[derive(Debug, Error)]
+enum XmppError {
+    #[error("stream closed")]
+    Closed,
+    #[error("authentication failed")]
+    AuthFailed,
+    #[error("server offers no TLS, set plaintext to log in anyway")]
+    NoTls,
+    #[error("server does not offer SASL PLAIN")]
+    NoPlain,
+    #[error("unexpected stanza: {0}")]
+    Unexpected(String),
+}
+
+/// a parsed stanza
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: HashMap<String, String>,
+    children: Vec<Element>,
+    text: String,
+    /// source of a top level element
+    raw: String,
+}
+
+impl Element {
+    fn from_start(e: &BytesStart) -> Self {
+        let attrs = e
+            .attributes()
+            .flatten()
+            .map(|attr| {
+                let key = String::from_utf8_lossy(attr.key.as_ref()).to_string();
+                let value = attr
+                    .unescape_value()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                (key, value)
+            })
+            .collect();
+        Element {
+            name: String::from_utf8_lossy(e.name().as_ref()).to_string(),
+            attrs,
+            ..Default::default()
+        }
+    }
+
+    fn attr(&self, key: &str) -> Option<&str> {
+        self.attrs.get(key).map(String::as_str)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+}
+
+/// connection to the server, buffers incoming bytes until a stanza is complete
+struct Connection {
+    reader: ReadHalf<Box<dyn Stream>>,
+    writer: Writer,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Stream>) -> Self {
+        let (reader, writer) = io::split(stream);
+        Connection {
+            reader,
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+            buf: Vec::new(),
+        }
+    }
+
+    /// the whole stream back, e.g. for STARTTLS
+    fn into_inner(self) -> Box<dyn Stream> {
+        let writer = Arc::try_unwrap(self.writer).unwrap_or_else(|_| panic!("writer in use"));
+        self.reader.unsplit(writer.into_inner())
+    }
+
+    async fn send(&self, data: &str) -> Result<()> {
+        write(&self.writer, data).await?;
+        Ok(())
+    }
+
+    /// read more bytes
+    async fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0; 4096];
+        let n = self.reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(XmppError::Closed.into());
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn text(&self) -> &str {
+        match std::str::from_utf8(&self.buf) {
+            Ok(s) => s,
+            Err(e) => std::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    /// open a new stream and wait for the server's stream header
+    async fn open(&mut self, domain: &str) -> Result<()> {
+        self.send(&format!(
+            "<?xml version='1.0'?><stream:stream to='{}' xmlns='jabber:client' \
+             xmlns:stream='http://etherx.jabber.org/streams' version='1.0'>",
+            escape(domain)
+        ))
+        .await?;
+        loop {
+            if let Some(n) = stream_header(self.text()) {
+                self.buf.drain(..n);
+                return Ok(());
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// wait for the next stanza, malformed input is logged and dropped
+    async fn next(&mut self) -> Result<Element> {
+        loop {
+            if let Err(e) = std::str::from_utf8(&self.buf) {
+                if let Some(len) = e.error_len() {
+                    let start = e.valid_up_to();
+                    log::error!("invalid utf-8, dropped {:?}", &self.buf[start..start + len]);
+                    self.buf.drain(start..start + len);
+                    continue;
+                }
+            }
+            let text = self.text();
+            if text.trim_start().starts_with("</stream:stream") {
+                return Err(XmppError::Closed.into());
+            }
+            match parse(text) {
+                Ok(Some((element, n))) => {
+                    log::trace!("< {}", &text[..n]);
+                    self.buf.drain(..n);
+                    return Ok(element);
+                }
+                Ok(None) => self.fill().await?,
+                Err((n, e)) => {
+                    log::error!("invalid xml: {e}, dropped {}", &text[..n]);
+                    self.buf.drain(..n);
+                }
+            }
+        }
+    }
+}
+
+async fn write(writer: &Writer, data: &str) -> io::Result<()> {
+    log::trace!("> {}", data);
+    let mut writer = writer.lock().await;
+    writer.write_all(data.as_bytes()).await?;
+    writer.flush().await
+}
+
+impl Xmpp {
+    pub fn new(name: String, config: XmppConfig) -> Self {
+        Xmpp {
+            name,
+            config,
+            accepted: watch::channel(false).0,
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn nick(&self) -> String {
+        self.config
+            .nick
+            .clone()
+            .unwrap_or_else(|| self.name.clone())
+    }
+
+    fn domain(&self) -> String {
+        self.config
+            .jid
+            .split('@')
+            .nth(1)
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+/// stream negotiation: STARTTLS, SASL PLAIN and resource binding
+///
+/// Without STARTTLS the password is only sent if `plaintext` is set.
+async fn login(name: &str, config: &XmppConfig, domain: &str) -> Result<Connection> {
+    let addr = match &config.addr {
+        Some(addr) => addr.clone(),
+        None => format!("{}:{}", domain, DEFAULT_PORT),
+    };
+    let tcp = TcpStream::connect(&addr).await?;
+    let mut conn = Connection::new(Box::new(tcp));
+
+    conn.open(domain).await?;
+    let mut features = conn.next().await?;
+    let tls = features.child("starttls").is_some();
+    if tls {
+        conn.send(&format!("<starttls xmlns='{}'/>", NS_TLS))
+            .await?;
+        let proceed = conn.next().await?;
+        if proceed.name != "proceed" {
+            return Err(XmppError::Unexpected(proceed.name).into());
+        }
+        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        let tls = connector.connect(domain, conn.into_inner()).await?;
+        conn = Connection::new(Box::new(tls));
+        conn.open(domain).await?;
+        features = conn.next().await?;
+    }
+    log::trace!("{:?}", features);
+    if !tls && config.plaintext != Some(true) {
+        return Err(XmppError::NoTls.into());
+    }
+    let plain = features.child("mechanisms").is_some_and(|mechanisms| {
+        mechanisms
+            .children
+            .iter()
+            .any(|m| m.name == "mechanism" && m.text == "PLAIN")
+    });
+    if !plain {
+        return Err(XmppError::NoPlain.into());
+    }
+
+    let user = config.jid.split('@').next().unwrap_or_default();
+    let credential = STANDARD.encode(format!("\0{}\0{}", user, config.password));
+    conn.send(&format!(
+        "<auth xmlns='{}' mechanism='PLAIN'>{}</auth>",
+        NS_SASL, credential
+    ))
+    .await?;
+    if conn.next().await?.name != "success" {
+        return Err(XmppError::AuthFailed.into());
+    }
+
+    conn.open(domain).await?;
+    conn.next().await?;
+    conn.send(&format!(
+        "<iq type='set' id='bind'><bind xmlns='{}'><resource>{}</resource></bind></iq>",
+        NS_BIND,
+        escape(name)
+    ))
+    .await?;
+    let bound = conn.next().await?;
+    if bound.attr("type") != Some("result") {
+        return Err(XmppError::Unexpected(bound.name).into());
+    }
+
+    Ok(conn)
+}
+
+/// announce presence and join the rooms
+async fn join(conn: &Connection, nick: &str, rooms: &[String]) -> Result<()> {
+    conn.send("<presence/>").await?;
+    for room in rooms {
+        conn.send(&format!(
+            "<presence to='{}/{}'><x xmlns='{}'><history maxstanzas='0'/></x></presence>",
+            escape(room),
+            escape(nick),
+            NS_MUC
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+/// handle stanzas until the stream ends, false once the bot stops listening
+async fn session(
+    conn: &mut Connection,
+    nick: &str,
+    rooms: &[String],
+    tx: &UnboundedSender<Message>,
+    accepted: &mut watch::Receiver<bool>,
+) -> bool {
+    loop {
+        let stanza = tokio::select! {
+            _ = stopped(accepted) => return false,
+            stanza = conn.next() => stanza,
+        };
+        let stanza = match stanza {
+            Ok(stanza) => stanza,
+            Err(e) => {
+                log::error!("read fail: {e}");
+                return true;
+            }
+        };
+
+        match stanza.name.as_str() {
+            "message" => {
+                if let Some(msg) = to_message(nick, rooms, &stanza) {
+                    if tx.send(msg).is_err() {
+                        log::error!("receiver dropped, stop reading");
+                        return false;
+                    }
+                }
+            }
+            "iq" if matches!(stanza.attr("type"), Some("get" | "set")) => {
+                // answer pings, refuse everything else
+                let id = escape(stanza.attr("id").unwrap_or_default());
+                let to = escape(stanza.attr("from").unwrap_or_default());
+                let reply = if stanza.attr("type") == Some("get") && stanza.child("ping").is_some()
+                {
+                    format!("<iq type='result' id='{}' to='{}'/>", id, to)
+                } else {
+                    format!(
+                        "<iq type='error' id='{}' to='{}'><error type='cancel'>\
+                         <service-unavailable xmlns='{}'/></error></iq>",
+                        id, to, NS_STANZAS
+                    )
+                };
+                if let Err(e) = conn.send(&reply).await {
+                    log::error!("write iq fail: {e}");
+                }
+            }
+            _ => log::trace!("{:?}", stanza),
+        }
+    }
+}
+
+#[async_trait]
+impl Server for Xmpp {
+    async fn connect(&self, tx: UnboundedSender<Message>) -> Result<JoinHandle<()>> {
+        let domain = self.domain();
+        let nick = self.nick();
+        let mut conn = login(&self.name, &self.config, &domain).await?;
+        log::trace!("Connected to the server!");
+        join(&conn, &nick, &self.config.rooms).await?;
+        *self.writer.lock().unwrap() = Some(conn.writer.clone());
+
+        self.accepted.send_replace(true);
+        let mut accepted = self.accepted.subscribe();
+
+        let name = self.name.clone();
+        let config = self.config.clone();
+        let writer = self.writer.clone();
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            loop {
+                let ended = session(&mut conn, &nick, &config.rooms, &tx, &mut accepted).await;
+                conn.send("</stream:stream>").await.ok();
+                *writer.lock().unwrap() = None;
+                if !ended {
+                    break;
+                }
+
+                // the stream ended, log in again
+                conn = loop {
+                    if !backoff.wait(&mut accepted).await {
+                        return;
+                    }
+                    let conn = match login(&name, &config, &domain).await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            log::error!("reconnect fail: {e}");
+                            continue;
+                        }
+                    };
+                    match join(&conn, &nick, &config.rooms).await {
+                        Ok(()) => break conn,
+                        Err(e) => log::error!("join fail: {e}"),
+                    }
+                };
+                log::trace!("Reconnected to the server!");
+                backoff.reset();
+                *writer.lock().unwrap() = Some(conn.writer.clone());
+            }
+        });
+
+        Ok(handle)
+    }
+
+    async fn disconnect(&self) {
+        log::trace!("disconnect");
+        self.accepted.send_replace(false);
+    }
+
+    async fn send(&self, channel: &str, message: &str) -> SendResult {
+        let kind = if self
+            .config
+            .rooms
+            .iter()
+            .any(|room| room.eq_ignore_ascii_case(channel))
+        {
+            "groupchat"
+        } else {
+            "chat"
+        };
+        let writer = self.writer.lock().unwrap().clone();
+        let writer = writer.ok_or(SendError::Disconnected)?;
+        let stanza = format!(
+            "<message to='{}' type='{}'><body>{}</body></message>",
+            escape(channel),
+            kind,
+            escape(message)
+        );
+        write(&writer, &stanza).await.map_err(|e| {
+            log::error!("write message fail: {e}");
+            SendError::Disconnected
+        })
+    }
+}
+
+fn to_message(nick: &str, rooms: &[String], stanza: &Element) -> Option<Message> {
+    let body = &stanza.child("body")?.text;
+    // skip room history
+    if stanza.child("delay").is_some() {
+        return None;
+    }
+
+    let from = stanza.attr("from")?;
+    let (bare, resource) = from.split_once('/').unwrap_or((from, ""));
+    let joined = rooms.iter().any(|room| room.eq_ignore_ascii_case(bare));
+    let (channel, sender) = match stanza.attr("type") {
+        Some("groupchat") => (bare, resource),
+        // private message from an occupant, answered in private too
+        _ if joined => (from, resource),
+        _ => (bare, bare.split('@').next().unwrap_or(bare)),
+    };
+    if sender.is_empty() || (stanza.attr("type") == Some("groupchat") && sender == nick) {
+        return None;
+    }
+
+    let mut msg = Message::new(channel, sender, body, MessageKind::Text);
+    msg.id = stanza.attr("id").map(String::from);
+    msg.direct = stanza.attr("type") != Some("groupchat");
+    msg.server = Some(ServerType::Xmpp);
+    msg.account = Some(from.to_string());
+    msg.raw = Some(stanza.raw.clone());
+    Some(msg)
+}
+
+/// length of `<?xml ...?><stream:stream ...>` if complete
+fn stream_header(text: &str) -> Option<usize> {
+    let start = text.find("<stream:stream")?;
+    let end = text[start..].find('>')?;
+    Some(start + end + 1)
+}
+
+/// first complete element in text and the number of bytes it spans,
+/// `Err` with the number of malformed bytes to drop and why
+fn parse(text: &str) -> Result<Option<(Element, usize)>, (usize, String)> {
+    let mut reader = Reader::from_str(text);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut start = 0;
+    // a stanza that is well-formed but unreadable is dropped as a whole
+    let mut invalid = None;
+    loop {
+        if stack.is_empty() {
+            start = reader.buffer_position();
+        }
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            // incomplete, wait for more
+            Err(quick_xml::Error::UnexpectedEof(_)) => return Ok(None),
+            // skip past the offending tag
+            Err(e) => {
+                let pos = reader.buffer_position();
+                return match text[pos..].find('>') {
+                    Some(end) => Err((pos + end + 1, e.to_string())),
+                    None => Ok(None),
+                };
+            }
+        };
+        let element = match event {
+            Event::Start(e) => {
+                stack.push(Element::from_start(&e));
+                continue;
+            }
+            Event::Empty(e) => Element::from_start(&e),
+            Event::End(e) => match stack.pop() {
+                Some(element) => element,
+                None => {
+                    let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                    let error = format!("unexpected </{}>", name);
+                    return Err((reader.buffer_position(), error));
+                }
+            },
+            Event::Text(t) => {
+                if let Some(parent) = stack.last_mut() {
+                    match t.unescape() {
+                        Ok(t) => parent.text.push_str(&t),
+                        Err(e) => invalid = invalid.or(Some(e.to_string())),
+                    }
+                }
+                continue;
+            }
+            Event::CData(t) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.text.push_str(&String::from_utf8_lossy(&t));
+                }
+                continue;
+            }
+            Event::Eof => return Ok(None),
+            _ => continue,
+        };
+
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(element),
+            None => {
+                let n = reader.buffer_position();
+                if let Some(e) = invalid {
+                    return Err((n, e));
+                }
+                let raw = text[start..n].trim_start().to_string();
+                return Ok(Some((Element { raw, ..element }, n)));
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::server::stub;
+
+    use super::*;
+
+    const HEADER: &str = "<?xml version='1.0'?><stream:stream from='local' id='s1' \
+                          xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' version='1.0'>";
+
+    fn config(addr: &str, plaintext: Option<bool>) -> XmppConfig {
+        XmppConfig {
+            jid: "hongbot@local".to_string(),
+            password: "secret".to_string(),
+            addr: Some(addr.to_string()),
+            rooms: vec!["ops@muc.local".to_string()],
+            nick: None,
+            plaintext,
+        }
+    }
+
+    /// read from the client until `needle` shows up
+    fn expect(stream: &mut TcpStream, buf: &mut String, needle: &str) -> String {
+        let mut chunk = [0; 4096];
+        while !buf.contains(needle) {
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0, "closed while waiting for {}", needle);
+            buf.push_str(&String::from_utf8_lossy(&chunk[..n]));
+        }
+        let end = buf.find(needle).unwrap() + needle.len();
+        buf.drain(..end).collect()
+    }
+
+    /// accept the next client and script its plaintext login
+    fn accept(listener: &TcpListener) -> (TcpStream, String) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = String::new();
+
+        expect(&mut stream, &mut buf, "<stream:stream");
+        write!(
+            stream,
+            "{}<stream:features><mechanisms xmlns='{}'><mechanism>PLAIN</mechanism></mechanisms></stream:features>",
+            HEADER, NS_SASL
+        )
+        .unwrap();
+        let auth = expect(&mut stream, &mut buf, "</auth>");
+        let credential = STANDARD.encode("\0hongbot\0secret");
+        assert!(auth.contains(&credential));
+        write!(stream, "<success xmlns='{}'/>", NS_SASL).unwrap();
+
+        expect(&mut stream, &mut buf, "<stream:stream");
+        write!(
+            stream,
+            "{}<stream:features><bind xmlns='{}'/></stream:features>",
+            HEADER, NS_BIND
+        )
+        .unwrap();
+        expect(&mut stream, &mut buf, "</iq>");
+        write!(
+            stream,
+            "<iq type='result' id='bind'><bind xmlns='{}'><jid>hongbot@local/hongbot</jid></bind></iq>",
+            NS_BIND
+        )
+        .unwrap();
+        (stream, buf)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(parse("<message from='a'><body>hi"), Ok(None)));
+        assert!(matches!(parse("<message from='a'"), Ok(None)));
+
+        let text = "<message from='room@muc/alice' type='groupchat'><body>a &amp; b</body></message><presence/>";
+        let (element, n) = parse(text).unwrap().unwrap();
+        assert_eq!(element.name, "message");
+        assert_eq!(element.attr("type"), Some("groupchat"));
+        assert_eq!(element.child("body").unwrap().text, "a & b");
+        assert_eq!(&text[n..], "<presence/>");
+
+        let rooms = ["room@muc".to_string()];
+        let msg = to_message("hongbot", &rooms, &element).unwrap();
+        assert_eq!(msg.channel, "room@muc");
+        assert_eq!(msg.nick, "alice");
+        assert_eq!(msg.message, "a & b");
+        assert!(!msg.direct);
+        assert_eq!(msg.raw.as_deref(), Some(&text[..n]));
+
+        let (element, _) =
+            parse("<message from='alice@local/phone' type='chat'><body>ping</body></message>")
+                .unwrap()
+                .unwrap();
+        let msg = to_message("hongbot", &rooms, &element).unwrap();
+        assert_eq!(msg.channel, "alice@local");
+        assert_eq!(msg.nick, "alice");
+        assert!(msg.direct);
+        assert_eq!(msg.account.as_deref(), Some("alice@local/phone"));
+
+        // private message from an occupant of a joined room
+        let (element, _) =
+            parse("<message from='room@muc/bob' type='chat'><body>psst</body></message>")
+                .unwrap()
+                .unwrap();
+        let msg = to_message("hongbot", &rooms, &element).unwrap();
+        assert_eq!(msg.channel, "room@muc/bob");
+        assert_eq!(msg.nick, "bob");
+        assert!(msg.direct);
+
+        // malformed stanzas are dropped whole, stray end tags on their own
+        let text = "<message><body>&bogus;</body></message><presence/>";
+        let (n, _) = parse(text).unwrap_err();
+        assert_eq!(&text[n..], "<presence/>");
+        let text = "</body></message><presence/>";
+        let (n, _) = parse(text).unwrap_err();
+        assert_eq!(&text[n..], "</message><presence/>");
+
+        assert_eq!(
+            stream_header("<?xml version='1.0'?><stream:stream id='1'><stream:features>"),
+            Some(43)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_muc() {
+        // scripted stand-in for an XMPP server
+        let (listener, addr) = stub::listen();
+        let (out_tx, mut out_rx) = unbounded_channel::<String>();
+        thread::spawn(move || {
+            let (mut stream, mut buf) = accept(&listener);
+            let join = expect(&mut stream, &mut buf, "</presence>");
+            out_tx.send(join).unwrap();
+
+            // history, own echo and a fresh message
+            write!(
+                stream,
+                "<message from='ops@muc.local/alice' type='groupchat'><body>old</body>\
+                 <delay xmlns='urn:xmpp:delay' stamp='2023-01-01T00:00:00Z'/></message>\
+                 <message from='ops@muc.local/hongbot' type='groupchat'><body>echo</body></message>\
+                 <message from='ops@muc.local/alice' type='groupchat'><body>&bogus;</body></message>\
+                 <message from='ops@muc.local/alice' type='groupchat'><body>hongbot: ping</body></message>\
+                 <iq type='get' id='p1' from='local'><ping xmlns='urn:xmpp:ping'/></iq>\
+                 <iq type='set' id='s1' from='local'><query xmlns='jabber:iq:roster'/></iq>"
+            )
+            .unwrap();
+
+            let pong = expect(&mut stream, &mut buf, "/>");
+            out_tx.send(pong).unwrap();
+            let refused = expect(&mut stream, &mut buf, "</iq>");
+            out_tx.send(refused).unwrap();
+            let reply = expect(&mut stream, &mut buf, "</message>");
+            out_tx.send(reply).unwrap();
+
+            write!(
+                stream,
+                "<message from='ops@muc.local/alice' type='chat'><body>psst</body></message>"
+            )
+            .unwrap();
+            let reply = expect(&mut stream, &mut buf, "</message>");
+            out_tx.send(reply).unwrap();
+        });
+
+        let xmpp = Xmpp::new("hongbot".to_string(), config(&addr.to_string(), Some(true)));
+        let (tx, mut rx) = unbounded_channel();
+        let handle = xmpp.connect(tx).await.unwrap();
+
+        let join = stub::recv(&mut out_rx).await;
+        assert!(join.contains("<presence to='ops@muc.local/hongbot'>"));
+
+        let msg = stub::recv_message(&mut rx, "ops@muc.local", "alice", "hongbot: ping").await;
+
+        let pong = stub::recv(&mut out_rx).await;
+        assert_eq!(pong, "<iq type='result' id='p1' to='local'/>");
+        let refused = stub::recv(&mut out_rx).await;
+        assert_eq!(
+            refused,
+            "<iq type='error' id='s1' to='local'><error type='cancel'>\
+             <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>"
+        );
+
+        // room jids are case-insensitive
+        assert_eq!(msg.channel, "ops@muc.local");
+        xmpp.send("OPS@muc.local", "alice: pong").await.unwrap();
+        let reply = stub::recv(&mut out_rx).await;
+        assert_eq!(
+            reply,
+            "<message to='OPS@muc.local' type='groupchat'><body>alice: pong</body></message>"
+        );
+
+        // private messages stay private
+        let msg = stub::recv(&mut rx).await;
+        assert_eq!(msg.channel, "ops@muc.local/alice");
+        assert_eq!(msg.nick, "alice");
+        xmpp.send(&msg.channel, "psst").await.unwrap();
+        let reply = stub::recv(&mut out_rx).await;
+        assert_eq!(
+            reply,
+            "<message to='ops@muc.local/alice' type='chat'><body>psst</body></message>"
+        );
+
+        xmpp.disconnect().await;
+        stub::join(handle).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (listener, addr) = stub::listen();
+        thread::spawn(move || {
+            // the first stream ends right after login
+            let (mut stream, mut buf) = accept(&listener);
+            expect(&mut stream, &mut buf, "</presence>");
+            write!(stream, "</stream:stream>").unwrap();
+
+            let (mut stream, mut buf) = accept(&listener);
+            expect(&mut stream, &mut buf, "</presence>");
+            write!(
+                stream,
+                "<message from='ops@muc.local/alice' type='groupchat'><body>hongbot: ping</body></message>"
+            )
+            .unwrap();
+            // keep the stream open until the client hangs up
+            stream.read_to_string(&mut buf).ok();
+        });
+
+        let xmpp = Xmpp::new("hongbot".to_string(), config(&addr.to_string(), Some(true)));
+        let (tx, mut rx) = unbounded_channel();
+        let handle = xmpp.connect(tx).await.unwrap();
+        stub::recv_message(&mut rx, "ops@muc.local", "alice", "hongbot: ping").await;
+
+        xmpp.disconnect().await;
+        stub::join(handle).await;
+    }
+
+    #[tokio::test]
+    async fn test_login_refused() {
+        let plain = format!(
+            "<mechanisms xmlns='{}'><mechanism>PLAIN</mechanism></mechanisms>",
+            NS_SASL
+        );
+        let scram = format!(
+            "<mechanisms xmlns='{}'><mechanism>SCRAM-SHA-1</mechanism></mechanisms>",
+            NS_SASL
+        );
+        for (features, plaintext, error) in [(plain, None, "no TLS"), (scram, Some(true), "PLAIN")]
+        {
+            let (listener, addr) = stub::listen();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = String::new();
+                expect(&mut stream, &mut buf, "<stream:stream");
+                write!(
+                    stream,
+                    "{}<stream:features>{}</stream:features>",
+                    HEADER, features
+                )
+                .unwrap();
+                // whatever the client sends until it hangs up
+                stream.read_to_string(&mut buf).ok();
+                buf
+            });
+
+            let xmpp = Xmpp::new("hongbot".to_string(), config(&addr.to_string(), plaintext));
+            let (tx, _rx) = unbounded_channel();
+            let e = xmpp.connect(tx).await.unwrap_err();
+            assert!(e.to_string().contains(error), "{}", e);
+            assert!(!server.join().unwrap().contains("<auth"));
+        }
+    }
+}
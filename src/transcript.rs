@@ -0,0 +1,262 @@
+use thiserror::Error;
+
+use crate::{
+    bot::{Bot, Message, MessageKind},
+    server::{
+        shell::{target, to_message},
+        test::{Sent, TestServer},
+    },
+};
+
+/// Bot behavior written as a shell session, e.g.
+///
+/// ```text
+///     you#shell> hongbot: foo is bar
+///     you#shell> hongbot: foo?
+/// hongbot#shell> bar
+/// ```
+///
+/// lines from the bot are the expected output of the input lines above
+/// them, blank lines and lines starting with `#` are ignored. Direct
+/// messages are written `alice@hongbot>` and answered as `hongbot@alice>`.
+#[derive(Debug)]
+pub struct Transcript {
+    lines: Vec<Line>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Line {
+    nick: String,
+    /// `#ops`, or the peer's nick for direct messages
+    channel: String,
+    message: String,
+}
+
+#[derive(Debug, Error)]
+pub enum TranscriptError {
+    #[error("line {0}: expected `<nick>#<channel>> <message>` or `<nick>@<nick>> <message>`")]
+    Parse(usize),
+    #[error("transcript mismatch\n{0}")]
+    Mismatch(String),
+}
+
+impl Line {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (prompt, message) = match line.split_once("> ") {
+            Some((prompt, message)) => (prompt, message),
+            None => (line.strip_suffix('>')?, ""),
+        };
+        let i = prompt.find(['#', '@'])?;
+        let (nick, channel) = prompt.split_at(i);
+        let channel = channel.strip_prefix('@').unwrap_or(channel);
+        let empty = channel.trim_start_matches('#').is_empty();
+        if nick.is_empty() || nick.contains(' ') || empty || channel.contains(' ') {
+            return None;
+        }
+        Some(Line {
+            nick: nick.to_string(),
+            channel: channel.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    fn direct(&self) -> bool {
+        !self.channel.starts_with('#')
+    }
+
+    /// message as the shell adapter sends it to the bot `name`
+    fn message(&self, name: &str) -> Message {
+        let channel = (!self.direct()).then_some(self.channel.as_str());
+        to_message(name, &self.nick, channel, &self.message, MessageKind::Text)
+    }
+
+    /// bot output as the shell adapter prints it
+    fn sent(name: &str, sent: &Sent) -> Self {
+        let message = match sent {
+            Sent::Send { message, .. }
+            | Sent::Notice { message, .. }
+            | Sent::ThreadReply { message, .. } => message.clone(),
+            Sent::Reply { nick, message, .. } => format!("{}: {}", nick, message),
+            Sent::Emote { action, .. } => format!("* {} {}", name, action),
+            Sent::Topic { topic, .. } => format!("* {} changed the topic to: {}", name, topic),
+            Sent::Join { channel } => format!("* {} has joined {}", name, channel),
+            Sent::Part { channel } => format!("* {} has left {}", name, channel),
+            Sent::React { reaction, .. } => format!("* {} reacted with {}", name, reaction),
+        };
+        Line {
+            nick: name.to_string(),
+            channel: sent.channel().to_string(),
+            message,
+        }
+    }
+
+    fn format(&self, width: usize) -> String {
+        format!(
+            "{:>width$}{}> {}",
+            self.nick,
+            target(&self.channel),
+            self.message
+        )
+        .trim_end()
+        .to_string()
+    }
+}
+
+impl Transcript {
+    pub fn parse(transcript: &str) -> Result<Self, TranscriptError> {
+        let mut lines = Vec::new();
+        for (i, line) in transcript.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            lines.push(Line::parse(line).ok_or(TranscriptError::Parse(i + 1))?);
+        }
+        Ok(Transcript { lines })
+    }
+
+    /// feeds the input lines to `bot`, which must be made with `server`,
+    /// and compares its output with the transcript
+    pub async fn verify(&self, bot: &mut Bot, server: &TestServer) -> Result<(), TranscriptError> {
+        let name = bot.name().to_string();
+        let mut actual = Vec::new();
+        let mut running = true;
+        for line in self.lines.iter().filter(|l| l.nick != name) {
+            actual.push(line.clone());
+            // input after shutdown is left unanswered
+            if !running {
+                continue;
+            }
+            running = bot.receive(line.message(&name)).await;
+            actual.extend(
+                server
+                    .drain(bot)
+                    .await
+                    .iter()
+                    .map(|sent| Line::sent(&name, sent)),
+            );
+        }
+
+        if actual == self.lines {
+            return Ok(());
+        }
+        let width = self
+            .lines
+            .iter()
+            .chain(&actual)
+            .map(|l| l.nick.len())
+            .max()
+            .unwrap_or_default();
+        let expected = self
+            .lines
+            .iter()
+            .map(|l| l.format(width))
+            .collect::<Vec<_>>();
+        let actual = actual.iter().map(|l| l.format(width)).collect::<Vec<_>>();
+        Err(TranscriptError::Mismatch(diff(&expected, &actual)))
+    }
+}
+
+/// line diff of expected (`-`) and actual (`+`) based on the longest common subsequence
+fn diff(expected: &[String], actual: &[String]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc};
+
+    use super::*;
+
+    fn bot(server: &TestServer) -> Bot {
+        let mut bot = Bot::with_server("hongbot".to_string(), Arc::new(server.clone()));
+        bot.install_actions();
+        bot
+    }
+
+    #[test]
+    fn test_line_parse() {
+        let line = Line::parse("    you#shell> hongbot: ping").unwrap();
+        assert_eq!(line.nick, "you");
+        assert_eq!(line.channel, "#shell");
+        assert_eq!(line.message, "hongbot: ping");
+        assert_eq!(line.format(7), "    you#shell> hongbot: ping");
+        assert_eq!(Line::parse("hongbot#shell>").unwrap().message, "");
+        assert!(Line::parse("hongbot: ping").is_none());
+
+        let line = Line::parse("  alice@hongbot> hongbot: ping").unwrap();
+        assert_eq!(line.channel, "hongbot");
+        let msg = line.message("hongbot");
+        assert_eq!(msg.channel, "alice");
+        assert!(msg.direct);
+        // the shell addresses direct messages to the bot
+        let msg = Line::parse("alice@hongbot> ping")
+            .unwrap()
+            .message("hongbot");
+        assert_eq!(msg.message, "hongbot ping");
+        assert_eq!(line.format(7), "  alice@hongbot> hongbot: ping");
+        assert!(Line::parse("alice@> hi").is_none());
+        assert_eq!(Line::parse("alice@b> hi").unwrap().channel, "b");
+    }
+
+    #[tokio::test]
+    async fn test_mismatch() {
+        let server = TestServer::new();
+        let transcript = Transcript::parse(
+            "
+    you#shell> hongbot: ping
+hongbot#shell> you: ping
+",
+        )
+        .unwrap();
+        let err = transcript.verify(&mut bot(&server), &server).await;
+        let Err(TranscriptError::Mismatch(diff)) = err else {
+            panic!("unexpected {:?}", err);
+        };
+        assert_eq!(
+            diff,
+            "      you#shell> hongbot: ping\n- hongbot#shell> you: ping\n+ hongbot#shell> you: pong"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transcripts() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("transcripts");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let transcript = Transcript::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+            let server = TestServer::new();
+            if let Err(e) = transcript.verify(&mut bot(&server), &server).await {
+                panic!("{}: {}", path.display(), e);
+            }
+        }
+    }
+}